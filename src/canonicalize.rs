@@ -0,0 +1,151 @@
+use hyper::Url;
+
+
+/// Optional URL canonicalization steps, that can merge urls pointing to different pages.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CanonicalizeOptions {
+    /// "/a/" becomes "/a"
+    pub strip_trailing_slash: bool,
+    /// "www.example.com" becomes "example.com"
+    pub strip_www: bool,
+}
+
+impl Default for CanonicalizeOptions {
+    fn default() -> Self {
+        CanonicalizeOptions {
+            strip_trailing_slash: false,
+            strip_www: false,
+        }
+    }
+}
+
+/// Canonical form of the url: the fragment is dropped, the host is lowercased,
+/// default port is dropped, dot segments are resolved, percent-encoding is normalized
+/// (unreserved characters are decoded, the rest use upper case hex digits),
+/// query parameters with empty values are removed and the rest are sorted.
+pub fn canonicalize_url(url: &Url, options: &CanonicalizeOptions) -> String {
+    let mut host = url.host_str().unwrap_or("").to_lowercase();
+    if options.strip_www && host.starts_with("www.") && host[4..].contains('.') {
+        host = host[4..].to_owned();
+    }
+    let mut path = remove_dot_segments(&normalize_percent_encoding(url.path()));
+    if options.strip_trailing_slash {
+        while path.len() > 1 && path.ends_with('/') {
+            path.pop();
+        }
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    let mut canonical = format!("{}://{}", url.scheme(), host);
+    if let Some(port) = url.port() {
+        canonical.push_str(&format!(":{}", port));
+    }
+    canonical.push_str(&path);
+    if let Some(query) = url.query() {
+        let mut params: Vec<String> = query.split('&')
+            .filter(|param| !param.is_empty() && !param.ends_with('='))
+            .map(normalize_percent_encoding)
+            .collect();
+        params.sort();
+        if !params.is_empty() {
+            canonical.push('?');
+            canonical.push_str(&params.join("&"));
+        }
+    }
+    canonical
+}
+
+fn is_unreserved(byte: u8) -> bool {
+    (byte as char).is_alphanumeric() && byte < 0x80 ||
+        byte == b'-' || byte == b'.' || byte == b'_' || byte == b'~'
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'...b'9' => Some(byte - b'0'),
+        b'a'...b'f' => Some(byte - b'a' + 10),
+        b'A'...b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Decode percent-encoded unreserved characters, and upper case the rest.
+fn normalize_percent_encoding(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut normalized = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] == b'%' && idx + 2 < bytes.len() {
+            let hex = (hex_value(bytes[idx + 1]), hex_value(bytes[idx + 2]));
+            if let (Some(high), Some(low)) = hex {
+                let decoded = high * 16 + low;
+                if is_unreserved(decoded) {
+                    normalized.push(decoded);
+                } else {
+                    normalized.extend(format!("%{:02X}", decoded).bytes());
+                }
+                idx += 3;
+                continue;
+            }
+        }
+        normalized.push(bytes[idx]);
+        idx += 1;
+    }
+    // Only ascii bytes were changed, so this is still valid utf-8
+    String::from_utf8(normalized).unwrap()
+}
+
+/// Resolve "." and ".." path segments.
+fn remove_dot_segments(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    let parts: Vec<&str> = path.split('/').collect();
+    for (idx, &segment) in parts.iter().enumerate() {
+        let is_last = idx == parts.len() - 1;
+        match segment {
+            "." | ".." => {
+                if segment == ".." && segments.len() > 1 {
+                    segments.pop();
+                }
+                if is_last {
+                    segments.push("");
+                }
+            },
+            _ => segments.push(segment),
+        }
+    }
+    segments.join("/")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn canonical(url: &str) -> String {
+        canonicalize_url(&url.parse().unwrap(), &CanonicalizeOptions::default())
+    }
+
+    #[test]
+    fn test_canonicalize() {
+        assert_eq!(canonical("http://A.com"), "http://a.com/");
+        assert_eq!(canonical("http://a.com:80/b#c"), "http://a.com/b");
+        assert_eq!(canonical("https://a.com:8443/b"), "https://a.com:8443/b");
+        assert_eq!(canonical("http://a.com/b/../c/./d"), "http://a.com/c/d");
+        assert_eq!(canonical("http://a.com/%7euser/%2e%2E/x"), "http://a.com/x");
+        assert_eq!(canonical("http://a.com/%7Ea%2fb%c3%a9"), "http://a.com/~a%2Fb%C3%A9");
+        assert_eq!(canonical("http://a.com/b?"), "http://a.com/b");
+        assert_eq!(canonical("http://a.com/b?z=1&a=2&&e=&f"), "http://a.com/b?a=2&f&z=1");
+        assert_eq!(canonical("http://a.com/b?q=%7e%3d"), "http://a.com/b?q=~%3D");
+        assert_eq!(canonical("http://www.a.com/b/"), "http://www.a.com/b/");
+    }
+
+    #[test]
+    fn test_options() {
+        let options = CanonicalizeOptions { strip_trailing_slash: true, strip_www: true };
+        let canonical = |url: &str| canonicalize_url(&url.parse().unwrap(), &options);
+        assert_eq!(canonical("http://www.a.com/b/"), "http://a.com/b");
+        assert_eq!(canonical("http://www.a.com/"), "http://a.com/");
+        assert_eq!(canonical("http://www.com/"), "http://www.com/");
+    }
+}
@@ -0,0 +1,101 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use dedupe::read_seen_set;
+use queue::RequestQueue;
+use request::Request;
+use settings::Settings;
+
+
+const FRONTIER_FILENAME: &'static str = "frontier.jl";
+const SEEN_FILENAME: &'static str = "seen.bin";
+
+/// Save queued and in flight requests, and seen request fingerprints, into job_dir.
+pub fn save(request_queue: &RequestQueue, job_dir: &str) -> io::Result<()> {
+    let job_dir = Path::new(job_dir);
+    try!(fs::create_dir_all(job_dir));
+    // Frontier is replaced first: if we crash before seen set is replaced,
+    // some requests might be repeated, but none will be lost.
+    let frontier_path = job_dir.join(FRONTIER_FILENAME);
+    let frontier_tmp_path = job_dir.join(format!("{}.tmp", FRONTIER_FILENAME));
+    {
+        let mut writer = BufWriter::new(try!(File::create(&frontier_tmp_path)));
+        for request in try!(request_queue.get_requests()) {
+            try!(write!(writer, "{}\n", request.to_json()));
+        }
+        try!(writer.flush());
+    }
+    try!(fs::rename(&frontier_tmp_path, &frontier_path));
+    let seen_path = job_dir.join(SEEN_FILENAME);
+    let seen_tmp_path = job_dir.join(format!("{}.tmp", SEEN_FILENAME));
+    {
+        let mut writer = BufWriter::new(try!(File::create(&seen_tmp_path)));
+        try!(request_queue.get_seen_requests().write(&mut writer));
+        try!(writer.flush());
+    }
+    try!(fs::rename(&seen_tmp_path, &seen_path));
+    Ok(())
+}
+
+/// Restore request queue saved with save.
+pub fn load(settings: &Settings, job_dir: &str) -> io::Result<RequestQueue> {
+    let job_dir = Path::new(job_dir);
+    let mut requests = Vec::new();
+    let frontier_file = BufReader::new(try!(File::open(job_dir.join(FRONTIER_FILENAME))));
+    for line in frontier_file.lines() {
+        let line = try!(line);
+        match Request::from_json(&line) {
+            Some(request) => requests.push(request),
+            None => error!("Error parsing frontier record \"{}\"", line),
+        }
+    }
+    let mut seen_file = BufReader::new(try!(File::open(job_dir.join(SEEN_FILENAME))));
+    let seen_requests = try!(read_seen_set(&settings.dedupe_storage, &mut seen_file));
+    info!("Loaded {} requests and {} seen fingerprints from {}",
+          requests.len(), seen_requests.len(), job_dir.display());
+    Ok(RequestQueue::restore(settings, seen_requests, requests))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use queue::RequestQueue;
+    use request::Request;
+    use settings::Settings;
+    use super::*;
+
+    #[test]
+    fn test_save_load() {
+        let job_dir = env::temp_dir().join("crawler-test-checkpoint");
+        let job_dir = job_dir.to_str().unwrap();
+        let mut settings = Settings::default();
+        settings.obey_robots_txt = false;
+        let mut queue = RequestQueue::new(&settings);
+        let seed = Request::from_str("http://domain-1.com/");
+        let redirected = seed.redirect("http://domain-2.com/".parse().unwrap(), 301);
+        queue.push(seed);
+        queue.push(redirected.clone());
+        save(&queue, job_dir).unwrap();
+        let mut loaded = load(&settings, job_dir).unwrap();
+        fs::remove_dir_all(job_dir).unwrap();
+        assert_eq!(loaded.get_seen_requests().len(), queue.get_seen_requests().len());
+        let mut popped = Vec::new();
+        while let Some(request) = loaded.pop() {
+            popped.push(request);
+        }
+        popped.sort_by(|a, b| a.url.as_str().cmp(b.url.as_str()));
+        let urls: Vec<_> = popped.iter().map(|request| request.url.as_str()).collect();
+        assert_eq!(urls, vec!["http://domain-1.com/", "http://domain-2.com/"]);
+        assert_eq!(popped, vec![Request::from_str("http://domain-1.com/"), redirected]);
+        // Seen fingerprints are restored, so crawled urls are not queued again
+        loaded.push(Request::from_str("http://domain-1.com/"));
+        loaded.push(Request::from_str("http://domain-3.com/"));
+        assert_eq!(loaded.pop().map(|request| request.url.as_str().to_owned()),
+                   Some("http://domain-3.com/".to_owned()));
+        assert!(loaded.pop().is_none());
+    }
+}
@@ -0,0 +1,278 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use hyper::Url;
+use hyper::client::{Connect, Registration};
+use hyper::net::{Blocked, Transport};
+use rotor::mio::{EventSet, Evented, PollOpt, Selector, Token};
+use vecio::Writev;
+
+
+#[derive(Debug)]
+struct Connection {
+    /// Scheme, host and port the connection is made to
+    key: String,
+    /// A request is being made on the connection
+    busy: bool,
+    last_used: Instant,
+    /// Over the idle limit, it is closed the next time it is read
+    closing: bool,
+}
+
+/// Keeps track of open connections, and closes the oldest idle ones
+/// when there are more than max_idle of them.
+#[derive(Debug)]
+pub struct ConnectionPool {
+    max_idle: usize,
+    next_id: u64,
+    connections: HashMap<u64, Connection>,
+}
+
+impl ConnectionPool {
+    pub fn new(max_idle: usize) -> Self {
+        ConnectionPool {
+            max_idle: max_idle,
+            next_id: 0,
+            connections: HashMap::new(),
+        }
+    }
+
+    /// Number of idle connections that are not closing.
+    pub fn n_idle(&self) -> usize {
+        self.connections.values().filter(|c| !c.busy && !c.closing).count()
+    }
+
+    /// Called when the response to a request to this url is finished:
+    /// the busy connection to its host that was used last becomes idle.
+    pub fn finish_request(&mut self, url: &Url) {
+        let key = connection_key(url);
+        let id = self.connections.iter()
+            .filter(|&(_, c)| c.busy && c.key == key)
+            .max_by_key(|&(_, c)| c.last_used)
+            .map(|(&id, _)| id);
+        if let Some(id) = id {
+            if let Some(connection) = self.connections.get_mut(&id) {
+                connection.busy = false;
+                connection.last_used = Instant::now();
+            }
+        }
+        self.close_over_limit();
+    }
+
+    fn open(&mut self, key: String) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.connections.insert(id, Connection {
+            key: key,
+            busy: true,
+            last_used: Instant::now(),
+            closing: false,
+        });
+        id
+    }
+
+    /// Request is written to the connection, so it is not idle any more.
+    fn start_request(&mut self, id: u64) {
+        if let Some(connection) = self.connections.get_mut(&id) {
+            connection.busy = true;
+            connection.closing = false;
+            connection.last_used = Instant::now();
+        }
+        self.close_over_limit();
+    }
+
+    fn is_closing(&self, id: u64) -> bool {
+        self.connections.get(&id).map_or(false, |connection| connection.closing)
+    }
+
+    fn remove(&mut self, id: u64) {
+        self.connections.remove(&id);
+    }
+
+    fn close_over_limit(&mut self) {
+        let mut idle: Vec<_> = self.connections.iter()
+            .filter(|&(_, c)| !c.busy && !c.closing)
+            .map(|(&id, c)| (c.last_used, id))
+            .collect();
+        if idle.len() <= self.max_idle {
+            return;
+        }
+        idle.sort();
+        let n_over = idle.len() - self.max_idle;
+        for &(_, id) in &idle[..n_over] {
+            if let Some(connection) = self.connections.get_mut(&id) {
+                debug!("Closing idle connection to {}", connection.key);
+                connection.closing = true;
+            }
+        }
+    }
+}
+
+/// Connections are reused for urls with the same key.
+fn connection_key(url: &Url) -> String {
+    format!("{}://{}:{}", url.scheme(), url.host_str().unwrap_or(""),
+            url.port_or_known_default().unwrap_or(0))
+}
+
+/// Connection registered in the pool. Once it is marked as closing, reads return
+/// end of file, so that the client drops it.
+#[derive(Debug)]
+pub struct PooledStream<T> {
+    inner: T,
+    id: u64,
+    pool: Arc<Mutex<ConnectionPool>>,
+}
+
+impl<T: Read> Read for PooledStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pool.lock().unwrap().is_closing(self.id) {
+            return Ok(0);
+        }
+        self.inner.read(buf)
+    }
+}
+
+impl<T: Write> Write for PooledStream<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pool.lock().unwrap().start_request(self.id);
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Writev> Writev for PooledStream<T> {
+    fn writev(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        self.pool.lock().unwrap().start_request(self.id);
+        self.inner.writev(bufs)
+    }
+}
+
+impl<T: Evented> Evented for PooledStream<T> {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt)
+                -> io::Result<()> {
+        self.inner.register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet,
+                  opts: PollOpt) -> io::Result<()> {
+        self.inner.reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.inner.deregister(selector)
+    }
+}
+
+impl<T: Transport> Transport for PooledStream<T> {
+    fn take_socket_error(&mut self) -> io::Result<()> {
+        self.inner.take_socket_error()
+    }
+
+    fn blocked(&self) -> Option<Blocked> {
+        self.inner.blocked()
+    }
+}
+
+impl<T> Drop for PooledStream<T> {
+    fn drop(&mut self) {
+        self.pool.lock().unwrap().remove(self.id);
+    }
+}
+
+/// Connector that registers connections made by the wrapped connector in the pool.
+pub struct PooledConnector<C: Connect> {
+    connector: C,
+    pool: Arc<Mutex<ConnectionPool>>,
+    /// Pool keys of connections being made, by connector key
+    pending: HashMap<C::Key, VecDeque<String>>,
+}
+
+impl<C: Connect> PooledConnector<C> {
+    pub fn new(connector: C, pool: Arc<Mutex<ConnectionPool>>) -> Self {
+        PooledConnector {
+            connector: connector,
+            pool: pool,
+            pending: HashMap::new(),
+        }
+    }
+}
+
+impl<C: Connect> Connect for PooledConnector<C> {
+    type Output = PooledStream<C::Output>;
+    type Key = C::Key;
+
+    fn key(&self, url: &Url) -> Option<Self::Key> {
+        self.connector.key(url)
+    }
+
+    fn connect(&mut self, url: &Url) -> io::Result<Self::Key> {
+        let key = try!(self.connector.connect(url));
+        self.pending.entry(key.clone()).or_insert_with(VecDeque::new)
+            .push_back(connection_key(url));
+        Ok(key)
+    }
+
+    fn connected(&mut self) -> Option<(Self::Key, io::Result<Self::Output>)> {
+        self.connector.connected().map(|(key, result)| {
+            let pool_key = self.pending.get_mut(&key).and_then(|pending| pending.pop_front())
+                .unwrap_or_else(String::new);
+            let result = result.map(|stream| {
+                let id = self.pool.lock().unwrap().open(pool_key);
+                PooledStream { inner: stream, id: id, pool: self.pool.clone() }
+            });
+            (key, result)
+        })
+    }
+
+    fn register(&mut self, registration: Registration) {
+        self.connector.register(registration)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Write};
+    use std::sync::{Arc, Mutex};
+
+    use hyper::Url;
+    use super::*;
+
+    fn stream(pool: &Arc<Mutex<ConnectionPool>>, url: &str) -> PooledStream<Cursor<Vec<u8>>> {
+        let url = Url::parse(url).unwrap();
+        let id = pool.lock().unwrap().open(connection_key(&url));
+        PooledStream { inner: Cursor::new(b"data".to_vec()), id: id, pool: pool.clone() }
+    }
+
+    #[test]
+    fn test_max_idle() {
+        let pool = Arc::new(Mutex::new(ConnectionPool::new(2)));
+        let a = Url::parse("http://a.com/").unwrap();
+        let b = Url::parse("http://b.com/").unwrap();
+        let mut a1 = stream(&pool, "http://a.com/");
+        let mut a2 = stream(&pool, "http://a.com/x");
+        let mut b1 = stream(&pool, "http://b.com/");
+        pool.lock().unwrap().finish_request(&a);
+        pool.lock().unwrap().finish_request(&a);
+        assert_eq!(pool.lock().unwrap().n_idle(), 2);
+        // Third idle connection is over the limit, the oldest one is closed
+        pool.lock().unwrap().finish_request(&b);
+        assert_eq!(pool.lock().unwrap().n_idle(), 2);
+        let mut buf = [0; 4];
+        let closed = [a1.read(&mut buf).unwrap() == 0, a2.read(&mut buf).unwrap() == 0];
+        assert_eq!(closed.iter().filter(|&&closed| closed).count(), 1);
+        assert_eq!(b1.read(&mut buf).unwrap(), 4);
+        // Reused connection is not idle
+        b1.write(b"request").unwrap();
+        assert_eq!(pool.lock().unwrap().n_idle(), 1);
+        drop(a1);
+        drop(a2);
+        drop(b1);
+        assert_eq!(pool.lock().unwrap().connections.len(), 0);
+    }
+}
@@ -0,0 +1,194 @@
+use std::cmp;
+use std::io::Read;
+
+use brotli2::read::BrotliDecoder;
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use hyper::header::{ContentEncoding, ContentLength, Encoding, Headers};
+
+
+/// Bodies that decompress to less than this are never considered decompression bombs.
+const MIN_DECOMPRESSED_LIMIT: usize = 1024 * 1024;
+
+/// Limits that guard against decompression bombs.
+#[derive(Debug, Clone)]
+pub struct DecompressionLimits {
+    /// Maximal decompressed body size in bytes
+    pub max_size: usize,
+    /// Maximal ratio of decompressed to compressed size
+    pub max_ratio: usize,
+    /// Decompressed bodies larger than this are truncated, not dropped
+    pub max_body_size: usize,
+}
+
+/// Encodings for Accept-Encoding request header.
+pub fn accepted_encodings() -> Vec<Encoding> {
+    vec![Encoding::Gzip, Encoding::Deflate, Encoding::EncodingExt("br".to_owned())]
+}
+
+/// Body is encoded with something other than identity.
+pub fn is_encoded(headers: &Headers) -> bool {
+    headers.get::<ContentEncoding>().map_or(false, |&ContentEncoding(ref encodings)| {
+        encodings.iter().any(|encoding| *encoding != Encoding::Identity)
+    })
+}
+
+/// Decode body according to Content-Encoding response header, returning the decoded body
+/// and whether it is truncated. A truncated body is decoded as far as the data goes.
+/// If the body was decoded, Content-Encoding is removed and Content-Length is updated,
+/// so that headers describe the decoded body.
+pub fn decode_content(headers: &mut Headers, body: Vec<u8>, limits: &DecompressionLimits,
+                      truncated: bool) -> Result<(Vec<u8>, bool), String> {
+    let encodings = match headers.get::<ContentEncoding>() {
+        Some(&ContentEncoding(ref encodings)) => encodings.clone(),
+        None => return Ok((body, truncated)),
+    };
+    let mut truncated = truncated;
+    let limit = cmp::min(limits.max_size, cmp::max(
+        MIN_DECOMPRESSED_LIMIT, body.len().saturating_mul(limits.max_ratio)));
+    let mut body = body;
+    // Encodings are listed in the order they were applied
+    for encoding in encodings.iter().rev() {
+        body = match *encoding {
+            Encoding::Identity => body,
+            Encoding::Gzip => {
+                let decoder = try!(GzDecoder::new(&body[..]).map_err(|e| e.to_string()));
+                try!(read_limited(decoder, limit, limits.max_body_size, &mut truncated))
+            },
+            Encoding::Deflate => {
+                // "deflate" should be zlib format, but some servers send raw deflate data
+                match read_limited(ZlibDecoder::new(&body[..]), limit, limits.max_body_size,
+                                   &mut truncated) {
+                    Ok(decoded) => decoded,
+                    Err(_) => try!(read_limited(DeflateDecoder::new(&body[..]), limit,
+                                                limits.max_body_size, &mut truncated)),
+                }
+            },
+            Encoding::EncodingExt(ref name) if name == "br" =>
+                try!(read_limited(BrotliDecoder::new(&body[..]), limit, limits.max_body_size,
+                                  &mut truncated)),
+            ref encoding => return Err(format!("unsupported Content-Encoding {}", encoding)),
+        };
+    }
+    headers.remove::<ContentEncoding>();
+    headers.set(ContentLength(body.len() as u64));
+    Ok((body, truncated))
+}
+
+/// Decompress up to limit bytes, failing if there are more (a decompression bomb).
+/// Output over max_body_size is cut off and marked as truncated.
+fn read_limited<R: Read>(decoder: R, limit: usize, max_body_size: usize, truncated: &mut bool)
+                         -> Result<Vec<u8>, String> {
+    let mut decoded = Vec::new();
+    let read_limit = cmp::min(limit, max_body_size);
+    if let Err(e) = decoder.take(read_limit as u64 + 1).read_to_end(&mut decoded) {
+        // Truncated data can not be decoded to the end, output read so far is kept
+        if !*truncated {
+            return Err(e.to_string());
+        }
+    }
+    if decoded.len() > limit {
+        Err(format!("decompressed body is larger than {} bytes", limit))
+    } else {
+        if decoded.len() > max_body_size {
+            decoded.truncate(max_body_size);
+            *truncated = true;
+        }
+        Ok(decoded)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use brotli2::write::BrotliEncoder;
+    use flate2::Compression;
+    use flate2::write::{DeflateEncoder, GzEncoder, ZlibEncoder};
+    use hyper::header::{ContentEncoding, ContentLength, Encoding, Headers};
+    use super::*;
+
+    const LIMITS: DecompressionLimits = DecompressionLimits {
+        max_size: 10 * 1024 * 1024,
+        max_ratio: 100,
+        max_body_size: 10 * 1024 * 1024,
+    };
+
+    fn headers(encodings: Vec<Encoding>) -> Headers {
+        let mut headers = Headers::new();
+        headers.set(ContentEncoding(encodings));
+        headers
+    }
+
+    fn compress<W: Write>(mut encoder: W, data: &[u8]) -> W {
+        encoder.write_all(data).unwrap();
+        encoder
+    }
+
+    #[test]
+    fn test_decode() {
+        let data = b"<p>hello</p>";
+        let gzip = compress(GzEncoder::new(Vec::new(), Compression::Default), data)
+            .finish().unwrap();
+        let zlib = compress(ZlibEncoder::new(Vec::new(), Compression::Default), data)
+            .finish().unwrap();
+        let deflate = compress(DeflateEncoder::new(Vec::new(), Compression::Default), data)
+            .finish().unwrap();
+        let brotli = compress(BrotliEncoder::new(Vec::new(), 6), data).finish().unwrap();
+        for &(ref encoding, ref body) in &[(Encoding::Gzip, gzip.clone()),
+                                           (Encoding::Deflate, zlib),
+                                           (Encoding::Deflate, deflate),
+                                           (Encoding::EncodingExt("br".to_owned()), brotli)] {
+            let mut headers = headers(vec![encoding.clone()]);
+            assert_eq!(decode_content(&mut headers, body.clone(), &LIMITS, false).unwrap(),
+                       (data.to_vec(), false));
+            assert!(headers.get::<ContentEncoding>().is_none());
+            assert_eq!(headers.get::<ContentLength>(), Some(&ContentLength(data.len() as u64)));
+        }
+        let mut headers = headers(vec![Encoding::Compress]);
+        assert!(decode_content(&mut headers, gzip, &LIMITS, false).is_err());
+        assert_eq!(decode_content(&mut Headers::new(), data.to_vec(), &LIMITS, false).unwrap(),
+                   (data.to_vec(), false));
+    }
+
+    #[test]
+    fn test_decompression_bomb() {
+        let data = vec![0u8; 5 * 1024 * 1024];
+        let gzip = compress(GzEncoder::new(Vec::new(), Compression::Best), &data)
+            .finish().unwrap();
+        assert!(decode_content(&mut headers(vec![Encoding::Gzip]), gzip.clone(), &LIMITS, false)
+                .is_err());
+        let limits = DecompressionLimits {
+            max_size: 10 * 1024 * 1024, max_ratio: 100000, max_body_size: 10 * 1024 * 1024 };
+        assert_eq!(decode_content(&mut headers(vec![Encoding::Gzip]), gzip.clone(), &limits,
+                                  false).unwrap().0.len(), data.len());
+        let limits = DecompressionLimits {
+            max_size: 1024, max_ratio: 100000, max_body_size: 10 * 1024 * 1024 };
+        assert!(decode_content(&mut headers(vec![Encoding::Gzip]), gzip, &limits, false)
+                .is_err());
+    }
+
+    #[test]
+    fn test_decode_truncated() {
+        let data: Vec<u8> = (0..20000).map(|i| i.to_string()).collect::<Vec<_>>()
+            .join(" ").into_bytes();
+        let gzip = compress(GzEncoder::new(Vec::new(), Compression::Default), &data)
+            .finish().unwrap();
+        let half = gzip[..gzip.len() / 2].to_vec();
+        // Cut stream fails to decode, unless it is known to be truncated
+        assert!(decode_content(&mut headers(vec![Encoding::Gzip]), half.clone(), &LIMITS, false)
+                .is_err());
+        let (decoded, truncated) = decode_content(
+            &mut headers(vec![Encoding::Gzip]), half, &LIMITS, true).unwrap();
+        assert!(truncated);
+        assert!(!decoded.is_empty() && decoded.len() < data.len());
+        assert_eq!(&decoded[..], &data[..decoded.len()]);
+        // Decoded body is cut at max_body_size
+        let limits = DecompressionLimits {
+            max_size: 10 * 1024 * 1024, max_ratio: 100, max_body_size: 1000 };
+        let (decoded, truncated) = decode_content(
+            &mut headers(vec![Encoding::Gzip]), gzip, &limits, false).unwrap();
+        assert!(truncated);
+        assert_eq!(&decoded[..], &data[..1000]);
+    }
+}
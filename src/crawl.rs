@@ -1,109 +1,339 @@
-use std::io::{Write};
-use std::fs::{File, OpenOptions};
 use std::clone::Clone;
-use std::str;
-use std::sync::mpsc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::AtomicUsize;
+use std::time::{Duration, Instant};
 
-use csv;
-use hyper::client::Client;
-use hyper::header::{Location};
+use hyper::client::{Client, Connect};
+use hyper::header::{ContentType, Location};
 use hyper::Url;
 use hyper::status::StatusCode;
-use rustc_serialize::json;
+use rustc_serialize::base64::{STANDARD, ToBase64};
 
-use downloader::{Handler, make_request};
-use link_extraction::extract_links;
+use checkpoint;
+use connection_pool::{ConnectionPool, PooledConnector};
+use decoding::decode_body;
+use downloader::{CountingConnector, Handler, make_request};
+use link_extraction::{ExtractedPage, extract_page};
+use link_graph::LinkGraphWriter;
+use media_type::{is_html, is_text};
+use mirror::MirrorWriter;
+use near_dup::{NearDupIndex, simhash};
+use output::RotatingWriter;
+use proxy::{Proxy, ProxyConnector, ProxyPool, supports_url};
 use queue::RequestQueue;
 use request::Request;
 use response::Response;
+use result::{CrawlResult, LinkRecord, RedirectRecord};
+use robots::{MAX_REDIRECTS as MAX_ROBOTS_REDIRECTS, RobotsTxt};
 use settings::Settings;
+use sinks::{Sink, JsonLinesWriter, ResponseLogWriter};
+use sqlite::SqliteWriter;
 use stats::CrawlStats;
+use warc::{WarcWriter, format_timestamp};
 
 
 pub fn crawl(seeds: Vec<Url>, settings: &Settings) {
-    let client = Client::<Handler>::configure()
+    crawl_with_sinks(seeds, settings, Vec::new());
+}
+
+/// Crawl, writing outcomes into sinks in addition to outputs configured in settings.
+pub fn crawl_with_sinks(seeds: Vec<Url>, settings: &Settings, sinks: Vec<Box<Sink>>) {
+    let mut request_queue = RequestQueue::new(settings);
+    for url in seeds {
+        if is_fetchable(&url, settings) {
+            request_queue.push(Request::new(url));
+        } else {
+            error!("Skipping seed {}: only http urls are supported through proxies", url);
+        }
+    }
+    run(request_queue, settings, sinks, false);
+}
+
+/// Continue the crawl from the last checkpoint in settings.job_dir.
+pub fn resume(settings: &Settings) {
+    resume_with_sinks(settings, Vec::new());
+}
+
+pub fn resume_with_sinks(settings: &Settings, sinks: Vec<Box<Sink>>) {
+    let job_dir = settings.job_dir.as_ref().expect("job_dir must be set to resume");
+    let request_queue = checkpoint::load(settings, job_dir)
+        .expect("Failed to load checkpoint");
+    run(request_queue, settings, sinks, true);
+}
+
+/// Sinks for outputs configured in settings.
+fn settings_sinks(settings: &Settings, resume: bool) -> Vec<Box<Sink>> {
+    let mut sinks: Vec<Box<Sink>> = Vec::new();
+    if let Some(ref urls_path) = settings.urls_path {
+        sinks.push(Box::new(ResponseLogWriter::new(RotatingWriter::new(
+            urls_path, &settings.output_rotation, settings.output_compression))));
+    }
+    if let Some(ref out_path) = settings.out_path {
+        sinks.push(Box::new(JsonLinesWriter::new(RotatingWriter::new(
+            out_path, &settings.output_rotation, settings.output_compression),
+            &settings.result_fields)));
+    }
+    if settings.link_graph_path.is_some() || settings.host_graph_path.is_some() {
+        let edges = settings.link_graph_path.as_ref().map(|path| RotatingWriter::new(
+            path, &settings.output_rotation, settings.output_compression));
+        sinks.push(Box::new(LinkGraphWriter::new(
+            edges, settings.host_graph_path.clone(), resume)));
+    }
+    if let Some(ref mirror_dir) = settings.mirror_dir {
+        sinks.push(Box::new(MirrorWriter::new(mirror_dir, settings.mirror_rewrite_links)));
+    }
+    if let Some(ref sqlite_path) = settings.sqlite_path {
+        sinks.push(Box::new(SqliteWriter::new(sqlite_path, &settings.result_fields)
+            .expect("Failed to open SQLite database")));
+    }
+    if let Some(ref warc_prefix) = settings.warc_prefix {
+        sinks.push(Box::new(WarcWriter::new(
+            warc_prefix, settings.warc_max_size, &settings.user_agent, settings.keep_alive)));
+    }
+    sinks
+}
+
+/// Url can be fetched with the configured connector.
+fn is_fetchable(url: &Url, settings: &Settings) -> bool {
+    settings.proxies.is_empty() || supports_url(url)
+}
+
+fn build_client<C>(connector: C, connections: &Arc<Mutex<ConnectionPool>>,
+                   settings: &Settings) -> Client<Handler>
+    where C: Connect + Send + 'static {
+    let max_idle_connections = if settings.keep_alive { settings.max_idle_connections } else { 0 };
+    Client::<Handler>::configure()
+        .connector(PooledConnector::new(connector, connections.clone()))
         // max_sockets set to larger value to have some capacity in reserve:
         // we sometimes might be sending a little bit more than concurrent_requests.
-        .max_sockets(2 * settings.concurrent_requests as usize)
+        // Idle keep-alive connections are counted too.
+        .max_sockets(2 * settings.concurrent_requests as usize + max_idle_connections)
+        .keep_alive(settings.keep_alive)
+        .keep_alive_timeout(Some(settings.keep_alive_timeout))
         .connect_timeout(Duration::from_secs(settings.timeout))
-        .build().expect("Failed to create a Client");
-    let (tx, rx) = mpsc::channel();
+        .build().expect("Failed to create a Client")
+}
 
-    let mut response_log_writer = settings.urls_path.clone().map(|ref urls_path|
-        ResponseLogWriter::new(urls_path));
-    let mut out_file = settings.out_path.clone().map(|ref out_path|
-        OpenOptions::new().create(true).append(true).open(out_path).unwrap());
-    let mut stats = CrawlStats::new(Duration::from_secs(20));
+fn run(mut request_queue: RequestQueue, settings: &Settings, extra_sinks: Vec<Box<Sink>>,
+       resume: bool) {
+    let n_connections = Arc::new(AtomicUsize::new(0));
+    let proxy_pool = if settings.proxies.is_empty() {
+        None
+    } else {
+        let proxies = settings.proxies.iter().map(|proxy_url| {
+            Proxy::parse(proxy_url).unwrap_or_else(|e| panic!("Invalid proxy url {}", e))
+        }).collect();
+        Some(Arc::new(Mutex::new(ProxyPool::new(
+            proxies, settings.proxy_assignment, settings.proxy_max_failures,
+            settings.proxy_bench_time))))
+    };
+    let connections = Arc::new(Mutex::new(ConnectionPool::new(settings.max_idle_connections)));
+    let client = match proxy_pool {
+        Some(ref proxy_pool) => build_client(
+            ProxyConnector::new(proxy_pool.clone(), n_connections.clone()), &connections,
+            settings),
+        None => build_client(
+            CountingConnector::new(n_connections.clone()), &connections, settings),
+    };
+    let (tx, rx) = mpsc::channel();
 
-    let mut request_queue = RequestQueue::new(settings);
-    for url in seeds {
-        request_queue.push(Request::new(url));
-    }
+    let mut sinks = settings_sinks(settings, resume);
+    sinks.extend(extra_sinks);
+    let mut stats = CrawlStats::new(Duration::from_secs(20), n_connections, proxy_pool);
+    let mut last_checkpoint = Instant::now();
+    let mut near_dup_index = settings.near_dup_distance.map(NearDupIndex::new);
 
     while !request_queue.is_empty() {
+        if last_checkpoint.elapsed() >= settings.checkpoint_interval {
+            if let Some(ref job_dir) = settings.job_dir {
+                save_checkpoint(&request_queue, job_dir);
+            }
+            for sink in &mut sinks {
+                sink.checkpoint();
+            }
+            last_checkpoint = Instant::now();
+        }
         // Send new requests, while there are any
-        while let Some(url) = request_queue.pop() {
+        while let Some(request) = request_queue.pop() {
             make_request(
-                url, &client, tx.clone(), settings.timeout, &settings.user_agent);
+                request, &client, tx.clone(), settings, &connections);
+        }
+        // Block until response or error (None) arrives,
+        // or until the next delayed domain is ready.
+        let (request, response) = match request_queue.time_to_next_ready() {
+            Some(wait) => match rx.recv_timeout(wait) {
+                Ok(result) => result,
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => panic!("Result channel closed"),
+            },
+            None => rx.recv().unwrap(),
+        };
+        if request.is_robots_txt {
+            if let Some(redirect) = robots_redirect(&request, &response, settings) {
+                request_queue.push_robots_redirect(redirect);
+            } else {
+                let (robots, outcome) = RobotsTxt::from_response(
+                    &response, &settings.robots_user_agent);
+                stats.record_robots(outcome);
+                request_queue.set_robots(&request, robots);
+            }
         }
-        // Block until response or error (None) arrives
-        let (request, response) = rx.recv().unwrap();
         // We received some response or error, decrement number of pending requests
         request_queue.decr_pending(&request);
         stats.record_response(&response);
-        if let Some(ref mut response_log_writer) = response_log_writer {
-            response_log_writer.write(&request, &response);
+        if response.as_ref().map_or(false, |response| response.truncated) {
+            stats.record_truncated(request.url.host_str().unwrap_or(""));
+        }
+        for sink in &mut sinks {
+            sink.write_response(&request, &response);
+        }
+        if request.is_robots_txt {
+            stats.maybe_report(&request_queue);
+            continue;
         }
         if let Some(ref response) = response {
-            let result = handle_response(&request, &response, &mut request_queue);
+            let result = handle_response(
+                &request, &response, &mut request_queue, &mut near_dup_index, settings);
             if let Some(result) = result {
-                if let Some(ref mut out_file) = out_file {
-                    write!(out_file, "{}\n", json::encode(&result).unwrap()).unwrap();
-                    out_file.flush().unwrap();
+                if !result.body_base64 {
+                    stats.record_encoding(&result.encoding, result.encoding_guessed);
+                }
+                if result.near_duplicate {
+                    stats.record_near_duplicate(request.url.host_str().unwrap_or(""));
+                }
+                if !(result.near_duplicate && settings.near_dup_skip_results) {
+                    for sink in &mut sinks {
+                        sink.write_result(&result);
+                    }
                 }
             }
         }
         stats.maybe_report(&request_queue);
     }
+    if let Some(ref job_dir) = settings.job_dir {
+        save_checkpoint(&request_queue, job_dir);
+    }
+    for sink in &mut sinks {
+        sink.close();
+    }
     client.close();
 }
 
-#[derive(RustcEncodable)]
-struct CrawlResult {
-    body: String,
-    url: String,
+fn save_checkpoint(request_queue: &RequestQueue, job_dir: &str) {
+    match checkpoint::save(request_queue, job_dir) {
+        Ok(()) => info!("Saved checkpoint to {}", job_dir),
+        Err(e) => error!("Error saving checkpoint to {}: {}", job_dir, e),
+    }
 }
 
-fn handle_response(request: &Request, response: &Response, request_queue: &mut RequestQueue)
-        -> Option<CrawlResult> {
+fn handle_response(request: &Request, response: &Response, request_queue: &mut RequestQueue,
+                   near_dup_index: &mut Option<NearDupIndex>, settings: &Settings)
+                   -> Option<CrawlResult> {
     match response.status {
         StatusCode::Ok => {
             if let Some(ref body) = response.body {
-                // TODO - detect encoding
-                if let Ok(ref body_text) = str::from_utf8(body) {
-                    for link in extract_links(&body_text, &request.url) {
-                        // TODO - an option to follow only in-domain links
-                        request_queue.push(Request::new(link));
-                    }
-                    Some(CrawlResult {
-                        body: body_text.to_string(),
-                        url: request.url.as_str().to_owned(),
-                    })
+                let media_type = response.media_type.as_ref()
+                    .map_or("", |media_type| media_type.as_str());
+                let html = is_html(media_type);
+                let (text, body_base64, encoding, encoding_guessed) =
+                    if html || is_text(media_type) {
+                        let decoded = decode_body(body, &response.headers);
+                        (decoded.text, false, decoded.encoding, decoded.guessed)
+                    } else {
+                        (body.to_base64(STANDARD), true, String::new(), false)
+                    };
+                // Links and metadata are extracted only from HTML
+                let page = if html {
+                    extract_page(&text, &request.url)
                 } else {
-                    info!("Dropping non utf8 body for {}", request.url);
-                    None
+                    ExtractedPage::default()
+                };
+                // Text of a truncated page is incomplete, so it is not indexed,
+                // and neither are pages with too little text to compare
+                let near_duplicate = match *near_dup_index {
+                    Some(ref mut index) if html && !response.truncated =>
+                        simhash(&page.text).map_or(false, |hash| index.check_and_add(hash)),
+                    _ => false,
+                };
+                if near_duplicate {
+                    debug!("Near-duplicate page at {}", request.url);
                 }
+                if !(near_duplicate && settings.near_dup_skip_links) {
+                    for link in &page.links {
+                        let link_request = request.follow(link.url.clone());
+                        if settings.scope.allows(request, &link_request) &&
+                                is_fetchable(&link_request.url, settings) {
+                            request_queue.push(link_request);
+                        }
+                    }
+                }
+                Some(CrawlResult {
+                    body: text,
+                    body_base64: body_base64,
+                    url: request.url.as_str().to_owned(),
+                    original_url: request.original_url().as_str().to_owned(),
+                    redirects: request.redirect_chain.iter().map(|redirect| {
+                        RedirectRecord {
+                            url: redirect.url.as_str().to_owned(),
+                            status: redirect.status,
+                        }
+                    }).collect(),
+                    referrer: request.referrer.as_ref().map(|url| url.as_str().to_owned()),
+                    depth: request.depth,
+                    status: response.status.to_u16(),
+                    headers: response.headers.iter()
+                        .map(|header| (header.name().to_owned(), header.value_string()))
+                        .collect(),
+                    content_type: response.headers.get::<ContentType>()
+                        .map(|content_type| content_type.to_string())
+                        .or_else(|| response.media_type.clone()),
+                    fetched_at: format_timestamp(response.fetched_at),
+                    download_time: response.download_time.as_secs() as f64 +
+                        1e-9 * response.download_time.subsec_nanos() as f64,
+                    body_length: response.wire_size,
+                    truncated: response.truncated,
+                    encoding: encoding,
+                    encoding_guessed: encoding_guessed,
+                    near_duplicate: near_duplicate,
+                    title: page.title,
+                    description: page.description,
+                    keywords: page.keywords,
+                    lang: page.lang,
+                    headings: page.headings,
+                    text: page.text,
+                    json_ld: page.json_ld,
+                    json_ld_errors: page.json_ld_errors,
+                    opengraph: page.opengraph,
+                    twitter: page.twitter,
+                    microdata: page.microdata,
+                    links: page.links.into_iter().map(|link| LinkRecord {
+                        url: link.url.as_str().to_owned(),
+                        text: link.text,
+                    }).collect(),
+                })
             } else {
                 None
             }
         },
         StatusCode::MovedPermanently | StatusCode::Found | StatusCode::SeeOther |
         StatusCode::TemporaryRedirect | StatusCode::PermanentRedirect => {
-            if let Some(url) = redirect_url(&response) {
-                // TODO - an option to follow only in-domain links
-                // TODO - limit number of redirects
-                request_queue.push(Request::new(url));
+            if let Some(url) = redirect_url(&request.url, &response) {
+                if request.n_redirects() < settings.max_redirects as usize {
+                    let redirect_request = request.redirect(url, response.status.to_u16());
+                    if !is_fetchable(&redirect_request.url, settings) {
+                        info!("Not following redirect from {} to {}: \
+                               only http urls are supported through proxies",
+                              request.url, redirect_request.url);
+                    } else if settings.scope.allows(request, &redirect_request) {
+                        request_queue.push(redirect_request);
+                    } else {
+                        debug!("Not following out of scope redirect from {} to {}",
+                               request.url, redirect_request.url);
+                    }
+                } else {
+                    info!("Too many redirects for {}, last one is to {}",
+                          request.original_url(), url);
+                }
             } else {
                 info!("Can not handle redirect for {}: no location", request.url);
             }
@@ -117,40 +347,37 @@ fn handle_response(request: &Request, response: &Response, request_queue: &mut R
 }
 
 
-fn redirect_url(response: &Response) -> Option<Url> {
-    if let Some(&Location(ref location)) = response.headers.get::<Location>() {
-        location.parse().ok()
-    } else {
-        None
+/// Request for the redirect target of a robots.txt request, if the redirect can be followed.
+/// Robots.txt redirects are followed even if the target is out of scope.
+fn robots_redirect(request: &Request, response: &Option<Response>, settings: &Settings)
+                   -> Option<Request> {
+    let response = match *response {
+        Some(ref response) if response.status.is_redirection() => response,
+        _ => return None,
+    };
+    let url = match redirect_url(&request.url, response) {
+        Some(url) => url,
+        None => return None,
+    };
+    if request.n_redirects() >= MAX_ROBOTS_REDIRECTS {
+        info!("Too many redirects for {}, last one is to {}", request.original_url(), url);
+        return None;
     }
-}
-
-struct ResponseLogWriter {
-    writer: csv::Writer<File>,
-}
-
-impl ResponseLogWriter {
-    fn new(path: &str) -> Self {
-        let file = OpenOptions::new().create(true).append(true).open(path).unwrap();
-        ResponseLogWriter {
-            writer: csv::Writer::from_writer(file),
-        }
+    let redirect_request = request.redirect(url, response.status.to_u16());
+    if !is_fetchable(&redirect_request.url, settings) {
+        info!("Not following redirect from {} to {}: \
+               only http urls are supported through proxies",
+              request.url, redirect_request.url);
+        return None;
     }
+    Some(redirect_request)
+}
 
-    fn write(&mut self, request: &Request, response: &Option<Response>) {
-        let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
-            Ok(duration) => {
-                let dt = duration.as_secs() as f64 + 1e-9 * duration.subsec_nanos() as f64;
-                format!("{:.6}", dt)
-            },
-            Err(_) => "-".to_owned()
-        };
-        let status = if let &Some(ref response) = response {
-            response.status.to_string()
-        } else {
-            "-".to_string()
-        };
-        self.writer.encode((timestamp, status, request.url.as_str())).unwrap();
-        self.writer.flush().unwrap();
+fn redirect_url(url: &Url, response: &Response) -> Option<Url> {
+    if let Some(&Location(ref location)) = response.headers.get::<Location>() {
+        // Location can be relative
+        url.join(location).ok()
+    } else {
+        None
     }
 }
@@ -0,0 +1,165 @@
+use std::cmp;
+
+use encoding::{DecoderTrap, EncodingRef};
+use encoding::all::{UTF_8, UTF_16BE, UTF_16LE, WINDOWS_1252};
+use encoding::label::encoding_from_whatwg_label;
+use hyper::header::{ContentType, Headers};
+use mime::{Attr, Mime};
+
+
+/// Response body decoded into UTF-8.
+pub struct DecodedBody {
+    pub text: String,
+    /// WHATWG name of the encoding
+    pub encoding: String,
+    /// Encoding was not declared in BOM, Content-Type or meta tags
+    pub guessed: bool,
+}
+
+/// Only this many bytes at the start of the body are checked for meta tags.
+const META_SNIFF_BYTES: usize = 1024;
+
+/// Decode body, detecting encoding from BOM, Content-Type charset and meta tags,
+/// in this order. If encoding is not declared, utf-8 is tried and windows-1252
+/// is used as a fallback.
+pub fn decode_body(body: &[u8], headers: &Headers) -> DecodedBody {
+    let declared = bom_encoding(body)
+        .or_else(|| content_type_encoding(headers).map(|encoding| (encoding, body)))
+        .or_else(|| meta_encoding(body).map(|encoding| (encoding, body)));
+    match declared {
+        Some((encoding, body)) => decode(encoding, body, false),
+        None => match String::from_utf8(body.to_vec()) {
+            Ok(text) => DecodedBody { text: text, encoding: "utf-8".to_owned(), guessed: true },
+            Err(_) => decode(WINDOWS_1252, body, true),
+        }
+    }
+}
+
+fn decode(encoding: EncodingRef, body: &[u8], guessed: bool) -> DecodedBody {
+    let text = match encoding.decode(body, DecoderTrap::Replace) {
+        Ok(text) => text,
+        Err(text) => text.into_owned(),
+    };
+    DecodedBody {
+        text: text,
+        encoding: encoding.whatwg_name().unwrap_or(encoding.name()).to_owned(),
+        guessed: guessed,
+    }
+}
+
+fn bom_encoding(body: &[u8]) -> Option<(EncodingRef, &[u8])> {
+    if body.starts_with(b"\xEF\xBB\xBF") {
+        Some((UTF_8 as EncodingRef, &body[3..]))
+    } else if body.starts_with(b"\xFE\xFF") {
+        Some((UTF_16BE as EncodingRef, &body[2..]))
+    } else if body.starts_with(b"\xFF\xFE") {
+        Some((UTF_16LE as EncodingRef, &body[2..]))
+    } else {
+        None
+    }
+}
+
+fn content_type_encoding(headers: &Headers) -> Option<EncodingRef> {
+    if let Some(&ContentType(Mime(_, _, ref params))) = headers.get::<ContentType>() {
+        for &(ref attr, ref value) in params {
+            if *attr == Attr::Charset {
+                return encoding_from_whatwg_label(&value.to_string());
+            }
+        }
+    }
+    None
+}
+
+fn meta_encoding(body: &[u8]) -> Option<EncodingRef> {
+    let head = &body[..cmp::min(body.len(), META_SNIFF_BYTES)];
+    let head = String::from_utf8_lossy(head).to_lowercase();
+    let mut rest = head.as_str();
+    while let Some(start) = rest.find("<meta") {
+        rest = &rest[start + "<meta".len()..];
+        let tag = match rest.find('>') {
+            Some(end) => &rest[..end],
+            None => rest,
+        };
+        // Handles both <meta charset="..."> and
+        // <meta http-equiv="Content-Type" content="text/html; charset=...">
+        if let Some(idx) = tag.find("charset") {
+            let value = tag[idx + "charset".len()..]
+                .trim_left()
+                .trim_left_matches('=')
+                .trim_left()
+                .trim_left_matches(|c| c == '"' || c == '\'');
+            let label: String = value.chars()
+                .take_while(|c| c.is_alphanumeric() || *c == '-' || *c == '_' ||
+                            *c == ':' || *c == '.')
+                .collect();
+            if let Some(encoding) = encoding_from_whatwg_label(&label) {
+                // Meta tag can not declare utf-16, because we were able to read it as ascii
+                return if encoding.name().starts_with("utf-16") {
+                    Some(UTF_8 as EncodingRef)
+                } else {
+                    Some(encoding)
+                };
+            }
+        }
+    }
+    None
+}
+
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::{ContentType, Headers};
+    use super::*;
+
+    fn decode_html(body: &[u8]) -> DecodedBody {
+        decode_body(body, &Headers::new())
+    }
+
+    #[test]
+    fn test_utf8() {
+        let decoded = decode_html("<p>привет</p>".as_bytes());
+        assert_eq!(decoded.text, "<p>привет</p>");
+        assert_eq!(decoded.encoding, "utf-8");
+        assert!(decoded.guessed);
+    }
+
+    #[test]
+    fn test_bom() {
+        let decoded = decode_html(b"\xEF\xBB\xBF<p>a</p>");
+        assert_eq!(decoded.text, "<p>a</p>");
+        assert_eq!(decoded.encoding, "utf-8");
+        assert!(!decoded.guessed);
+    }
+
+    #[test]
+    fn test_content_type() {
+        let mut headers = Headers::new();
+        headers.set(ContentType("text/html; charset=windows-1251".parse().unwrap()));
+        let decoded = decode_body(b"<p>\xEF\xF0\xE8\xE2\xE5\xF2</p>", &headers);
+        assert_eq!(decoded.text, "<p>привет</p>");
+        assert_eq!(decoded.encoding, "windows-1251");
+        assert!(!decoded.guessed);
+    }
+
+    #[test]
+    fn test_meta() {
+        let decoded = decode_html(b"<html><head><meta charset=\"koi8-r\"></head>\
+                                    <p>\xD0\xD2\xC9\xD7\xC5\xD4</p>");
+        assert!(decoded.text.ends_with("<p>привет</p>"));
+        assert_eq!(decoded.encoding, "koi8-r");
+        assert!(!decoded.guessed);
+        let decoded = decode_html(b"<meta http-equiv=\"Content-Type\" \
+                                    content=\"text/html; charset=windows-1251\">\
+                                    <p>\xEF\xF0\xE8\xE2\xE5\xF2</p>");
+        assert!(decoded.text.ends_with("<p>привет</p>"));
+        assert_eq!(decoded.encoding, "windows-1251");
+    }
+
+    #[test]
+    fn test_fallback() {
+        let decoded = decode_html(b"<p>caf\xE9</p>");
+        assert_eq!(decoded.text, "<p>café</p>");
+        assert_eq!(decoded.encoding, "windows-1252");
+        assert!(decoded.guessed);
+    }
+}
@@ -0,0 +1,356 @@
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+
+
+/// How request fingerprints are stored for dedupe.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DedupeStorage {
+    /// Exact set of fingerprints, grows without bound
+    Exact,
+    /// Scalable bloom filter: uses much less memory, but some new requests
+    /// will be considered already seen, with the given false positive rate.
+    /// The filter grows when more than expected_size fingerprints are added.
+    Bloom { expected_size: usize, false_positive_rate: f64 },
+}
+
+/// Set of fingerprints of seen requests.
+pub trait SeenSet {
+    /// Add fingerprint, returning true if it was not seen before.
+    fn insert(&mut self, fingerprint: u64) -> bool;
+    /// Number of added fingerprints.
+    fn len(&self) -> usize;
+    /// Fraction of bits set, for probabilistic sets.
+    fn fill_ratio(&self) -> Option<f64>;
+    /// Estimated probability that a new fingerprint is reported as seen.
+    fn false_positive_rate(&self) -> Option<f64>;
+    fn write(&self, writer: &mut Write) -> io::Result<()>;
+}
+
+pub fn new_seen_set(storage: &DedupeStorage) -> Box<SeenSet> {
+    match *storage {
+        DedupeStorage::Exact => Box::new(HashSet::new()),
+        DedupeStorage::Bloom { expected_size, false_positive_rate } =>
+            Box::new(ScalableBloomFilter::new(expected_size, false_positive_rate)),
+    }
+}
+
+/// Read seen set written with SeenSet::write.
+pub fn read_seen_set(storage: &DedupeStorage, reader: &mut Read) -> io::Result<Box<SeenSet>> {
+    match *storage {
+        DedupeStorage::Exact => {
+            let mut seen_set = HashSet::new();
+            while let Some(fingerprint) = try!(read_u64_or_eof(reader)) {
+                seen_set.insert(fingerprint);
+            }
+            Ok(Box::new(seen_set))
+        },
+        DedupeStorage::Bloom { .. } => {
+            let filter = try!(ScalableBloomFilter::read(reader));
+            Ok(Box::new(filter))
+        }
+    }
+}
+
+impl SeenSet for HashSet<u64> {
+    fn insert(&mut self, fingerprint: u64) -> bool {
+        HashSet::insert(self, fingerprint)
+    }
+
+    fn len(&self) -> usize {
+        HashSet::len(self)
+    }
+
+    fn fill_ratio(&self) -> Option<f64> {
+        None
+    }
+
+    fn false_positive_rate(&self) -> Option<f64> {
+        None
+    }
+
+    fn write(&self, writer: &mut Write) -> io::Result<()> {
+        for &fingerprint in self {
+            try!(write_u64(writer, fingerprint));
+        }
+        Ok(())
+    }
+}
+
+const BLOOM_MAGIC: &'static [u8; 8] = b"SBLOOM01";
+/// Each next filter is this many times larger
+const GROWTH_FACTOR: usize = 2;
+/// Each next filter has false positive rate multiplied by this,
+/// so that the overall rate stays below the target.
+const TIGHTENING_RATIO: f64 = 0.5;
+
+/// Scalable bloom filter (Almeida et al., 2007): a series of bloom filters
+/// of growing capacity and decreasing false positive rate.
+pub struct ScalableBloomFilter {
+    filters: Vec<BloomFilter>,
+}
+
+struct BloomFilter {
+    bits: Vec<u64>,
+    n_bits: u64,
+    n_hashes: u64,
+    capacity: u64,
+    count: u64,
+    n_set_bits: u64,
+}
+
+impl ScalableBloomFilter {
+    pub fn new(expected_size: usize, false_positive_rate: f64) -> Self {
+        let capacity = if expected_size > 0 { expected_size } else { 1 };
+        ScalableBloomFilter {
+            filters: vec![
+                BloomFilter::new(capacity as u64, false_positive_rate * (1. - TIGHTENING_RATIO))],
+        }
+    }
+
+    fn contains(&self, fingerprint: u64) -> bool {
+        self.filters.iter().any(|filter| filter.contains(fingerprint))
+    }
+
+    fn read(reader: &mut Read) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        try!(reader.read_exact(&mut magic));
+        if &magic != BLOOM_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a bloom filter"));
+        }
+        let n_filters = try!(read_u64(reader));
+        let mut filters = Vec::new();
+        for _ in 0..n_filters {
+            filters.push(try!(BloomFilter::read(reader)));
+        }
+        if filters.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Empty bloom filter"));
+        }
+        Ok(ScalableBloomFilter { filters: filters })
+    }
+}
+
+impl SeenSet for ScalableBloomFilter {
+    fn insert(&mut self, fingerprint: u64) -> bool {
+        if self.contains(fingerprint) {
+            return false;
+        }
+        let next_filter = {
+            let last = self.filters.last_mut().unwrap();
+            last.insert(fingerprint);
+            if last.count >= last.capacity {
+                Some(BloomFilter::new(last.capacity * GROWTH_FACTOR as u64,
+                                      last.false_positive_rate() * TIGHTENING_RATIO))
+            } else {
+                None
+            }
+        };
+        if let Some(next_filter) = next_filter {
+            self.filters.push(next_filter);
+        }
+        true
+    }
+
+    fn len(&self) -> usize {
+        self.filters.iter().map(|filter| filter.count as usize).sum()
+    }
+
+    fn fill_ratio(&self) -> Option<f64> {
+        let n_set_bits: u64 = self.filters.iter().map(|filter| filter.n_set_bits).sum();
+        let n_bits: u64 = self.filters.iter().map(|filter| filter.n_bits).sum();
+        Some(n_set_bits as f64 / n_bits as f64)
+    }
+
+    fn false_positive_rate(&self) -> Option<f64> {
+        let p_negative = self.filters.iter()
+            .fold(1., |p, filter| p * (1. - filter.current_false_positive_rate()));
+        Some(1. - p_negative)
+    }
+
+    fn write(&self, writer: &mut Write) -> io::Result<()> {
+        try!(writer.write_all(BLOOM_MAGIC));
+        try!(write_u64(writer, self.filters.len() as u64));
+        for filter in &self.filters {
+            try!(filter.write(writer));
+        }
+        Ok(())
+    }
+}
+
+impl BloomFilter {
+    fn new(capacity: u64, false_positive_rate: f64) -> Self {
+        let ln2 = 2f64.ln();
+        let n_bits = (-(capacity as f64) * false_positive_rate.ln() / (ln2 * ln2)).ceil() as u64;
+        let n_bits = if n_bits > 64 { n_bits } else { 64 };
+        let n_hashes = (-false_positive_rate.log2()).ceil() as u64;
+        BloomFilter {
+            bits: vec![0; ((n_bits + 63) / 64) as usize],
+            n_bits: n_bits,
+            n_hashes: if n_hashes > 0 { n_hashes } else { 1 },
+            capacity: capacity,
+            count: 0,
+            n_set_bits: 0,
+        }
+    }
+
+    /// Bit indices for the fingerprint, using double hashing.
+    fn indices(&self, fingerprint: u64) -> Vec<u64> {
+        let h1 = fingerprint;
+        let h2 = mix(fingerprint) | 1;
+        (0..self.n_hashes)
+            .map(|i| h1.wrapping_add(i.wrapping_mul(h2)) % self.n_bits)
+            .collect()
+    }
+
+    fn contains(&self, fingerprint: u64) -> bool {
+        self.indices(fingerprint).iter()
+            .all(|&idx| self.bits[(idx / 64) as usize] & (1 << (idx % 64)) != 0)
+    }
+
+    fn insert(&mut self, fingerprint: u64) {
+        for idx in self.indices(fingerprint) {
+            let word = &mut self.bits[(idx / 64) as usize];
+            let mask = 1 << (idx % 64);
+            if *word & mask == 0 {
+                *word |= mask;
+                self.n_set_bits += 1;
+            }
+        }
+        self.count += 1;
+    }
+
+    /// False positive rate this filter was designed for.
+    fn false_positive_rate(&self) -> f64 {
+        0.5f64.powf(self.n_hashes as f64)
+    }
+
+    /// False positive rate estimated from the number of set bits.
+    fn current_false_positive_rate(&self) -> f64 {
+        (self.n_set_bits as f64 / self.n_bits as f64).powf(self.n_hashes as f64)
+    }
+
+    fn write(&self, writer: &mut Write) -> io::Result<()> {
+        for &value in &[self.n_bits, self.n_hashes, self.capacity, self.count, self.n_set_bits] {
+            try!(write_u64(writer, value));
+        }
+        for &word in &self.bits {
+            try!(write_u64(writer, word));
+        }
+        Ok(())
+    }
+
+    fn read(reader: &mut Read) -> io::Result<Self> {
+        let n_bits = try!(read_u64(reader));
+        let n_hashes = try!(read_u64(reader));
+        let capacity = try!(read_u64(reader));
+        let count = try!(read_u64(reader));
+        let n_set_bits = try!(read_u64(reader));
+        let mut bits = Vec::new();
+        for _ in 0..(n_bits + 63) / 64 {
+            bits.push(try!(read_u64(reader)));
+        }
+        Ok(BloomFilter {
+            bits: bits,
+            n_bits: n_bits,
+            n_hashes: n_hashes,
+            capacity: capacity,
+            count: count,
+            n_set_bits: n_set_bits,
+        })
+    }
+}
+
+/// Finalizer from splitmix64, to get a second hash from the fingerprint.
+fn mix(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+fn write_u64(writer: &mut Write, value: u64) -> io::Result<()> {
+    let mut bytes = [0u8; 8];
+    for i in 0..8 {
+        bytes[i] = (value >> (8 * i)) as u8;
+    }
+    writer.write_all(&bytes)
+}
+
+fn read_u64(reader: &mut Read) -> io::Result<u64> {
+    match try!(read_u64_or_eof(reader)) {
+        Some(value) => Ok(value),
+        None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Unexpected end of file")),
+    }
+}
+
+fn read_u64_or_eof(reader: &mut Read) -> io::Result<Option<u64>> {
+    let mut bytes = [0u8; 8];
+    match reader.read_exact(&mut bytes) {
+        Ok(()) => {
+            let mut value = 0u64;
+            for i in 0..8 {
+                value |= (bytes[i] as u64) << (8 * i);
+            }
+            Ok(Some(value))
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bloom() -> Box<SeenSet> {
+        new_seen_set(&DedupeStorage::Bloom { expected_size: 1000, false_positive_rate: 0.01 })
+    }
+
+    #[test]
+    fn test_exact() {
+        let mut seen_set = new_seen_set(&DedupeStorage::Exact);
+        assert!(seen_set.insert(1));
+        assert!(!seen_set.insert(1));
+        assert!(seen_set.insert(2));
+        assert_eq!(seen_set.len(), 2);
+        assert_eq!(seen_set.fill_ratio(), None);
+    }
+
+    #[test]
+    fn test_bloom() {
+        let mut seen_set = bloom();
+        let n = 10000;
+        for i in 0..n {
+            seen_set.insert(mix(i));
+        }
+        // Filter grows beyond expected size, and all added fingerprints are seen
+        for i in 0..n {
+            assert!(!seen_set.insert(mix(i)));
+        }
+        let n_false_positives = (n..2 * n).filter(|&i| !seen_set.insert(mix(i))).count();
+        assert!((n_false_positives as f64) < 0.02 * n as f64);
+        let fill_ratio = seen_set.fill_ratio().unwrap();
+        assert!(fill_ratio > 0.1 && fill_ratio < 0.9);
+        assert!(seen_set.false_positive_rate().unwrap() < 0.02);
+    }
+
+    #[test]
+    fn test_write_read() {
+        let storage = DedupeStorage::Bloom { expected_size: 100, false_positive_rate: 0.01 };
+        let mut seen_set = bloom();
+        for i in 0..500 {
+            seen_set.insert(i);
+        }
+        let mut data = Vec::new();
+        seen_set.write(&mut data).unwrap();
+        let mut loaded = read_seen_set(&storage, &mut &data[..]).unwrap();
+        assert_eq!(loaded.len(), 500);
+        assert_eq!(loaded.fill_ratio(), seen_set.fill_ratio());
+        assert!(!loaded.insert(10));
+        let mut exact = new_seen_set(&DedupeStorage::Exact);
+        exact.insert(10);
+        let mut data = Vec::new();
+        exact.write(&mut data).unwrap();
+        assert!(read_seen_set(&storage, &mut &data[..]).is_err());
+    }
+}
@@ -1,20 +1,24 @@
-use std::io;
-use std::sync::mpsc;
-use std::time::Duration;
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
 use hyper;
-use hyper::client::{Client, Request as HyperRequest, Response as HyperResponse,
-    DefaultTransport as HttpStream};
-use hyper::header::{Connection, ContentType};
-use hyper::{Decoder, Encoder, Next};
+use hyper::client::{Client, Connect, DefaultConnector, Registration,
+    Request as HyperRequest, Response as HyperResponse};
+use hyper::net::Transport;
+use hyper::header::{AcceptEncoding, Connection, ContentLength, Headers, qitem};
+use hyper::{Decoder, Encoder, Next, Url};
 use hyper::status::StatusCode;
-use hyper::header::{Headers, UserAgent};
-use mime::Mime;
-use mime::TopLevel::Text;
-use mime::SubLevel::Html;
+use hyper::header::UserAgent;
+use hyper::version::HttpVersion;
 
+use connection_pool::ConnectionPool;
+use content_encoding::{DecompressionLimits, accepted_encodings, decode_content, is_encoded};
+use media_type::{SNIFF_BYTES, is_allowed, is_generic, media_type, sniff};
 use request::Request;
 use response::Response;
+use settings::Settings;
 
 
 pub type ResultSender = mpsc::Sender<(Request, Option<Response>)>;
@@ -24,57 +28,233 @@ pub struct Handler {
     request: Request,
     response: Option<Response>,
     sender: ResultSender,
+    started: Instant,
     timeout: u64,
     user_agent: String,
+    keep_alive: bool,
+    max_body_size: usize,
+    download_content_types: Vec<String>,
+    /// Content-Type is missing or generic, media type is sniffed from the body
+    sniff_media_type: bool,
+    decompression_limits: DecompressionLimits,
+    /// Connection becomes idle when the response is finished
+    connections: Arc<Mutex<ConnectionPool>>,
 }
 
 pub fn make_request(request: Request, client: &Client<Handler>, tx: ResultSender,
-                    timeout: u64, user_agent: &str)  {
+                    settings: &Settings, connections: &Arc<Mutex<ConnectionPool>>)  {
     let url = request.url.clone();
-    let handler = Handler {
-        request: request,
-        response: None,
-        sender: tx,
-        timeout: timeout,
-        user_agent: user_agent.to_owned(),
-    };
-    client.request(url, handler).unwrap();
+    client.request(url, Handler::new(request, tx, settings, connections.clone())).unwrap();
 }
 
-fn is_html(headers: &Headers) -> bool {
-    match headers.get::<ContentType>() {
-        Some(content_type) => match content_type {
-            &ContentType(Mime(Text, Html, _)) => true,
-            _ => false
-        },
-        None => false
+/// Default connector that counts opened connections, to measure connection reuse.
+pub struct CountingConnector {
+    connector: DefaultConnector,
+    n_connections: Arc<AtomicUsize>,
+}
+
+impl CountingConnector {
+    pub fn new(n_connections: Arc<AtomicUsize>) -> Self {
+        CountingConnector {
+            connector: DefaultConnector::default(),
+            n_connections: n_connections,
+        }
+    }
+}
+
+impl Connect for CountingConnector {
+    type Output = <DefaultConnector as Connect>::Output;
+    type Key = <DefaultConnector as Connect>::Key;
+
+    fn key(&self, url: &Url) -> Option<Self::Key> {
+        self.connector.key(url)
+    }
+
+    fn connect(&mut self, url: &Url) -> io::Result<Self::Key> {
+        self.n_connections.fetch_add(1, Ordering::Relaxed);
+        self.connector.connect(url)
+    }
+
+    fn connected(&mut self) -> Option<(Self::Key, io::Result<Self::Output>)> {
+        self.connector.connected()
+    }
+
+    fn register(&mut self, registration: Registration) {
+        self.connector.register(registration)
     }
 }
 
 impl Handler {
+    pub fn new(request: Request, tx: ResultSender, settings: &Settings,
+               connections: Arc<Mutex<ConnectionPool>>) -> Self {
+        Handler {
+            request: request,
+            response: None,
+            sender: tx,
+            started: Instant::now(),
+            timeout: settings.timeout,
+            user_agent: settings.user_agent.clone(),
+            keep_alive: settings.keep_alive,
+            max_body_size: settings.max_body_size,
+            download_content_types: settings.download_content_types.clone(),
+            sniff_media_type: false,
+            decompression_limits: DecompressionLimits {
+                max_size: settings.max_decompressed_size,
+                max_ratio: settings.max_compression_ratio,
+                max_body_size: settings.max_body_size,
+            },
+            connections: connections,
+        }
+    }
+
     fn read(&self) -> Next {
         Next::read().timeout(Duration::from_secs(self.timeout))
     }
 
     fn return_response(&self) -> Next {
         self.send_result();
+        self.connections.lock().unwrap().finish_request(&self.request.url);
         Next::end()
     }
 
     fn send_result(&self) {
-        self.sender.send((self.request.clone(), self.response.clone())).unwrap();
+        let response = self.response.clone().map(|mut response| {
+            response.download_time = self.started.elapsed();
+            if let Some(body) = response.body.take() {
+                response.wire_size = body.len();
+                if is_encoded(&response.headers) {
+                    response.raw_body = Some(body.clone());
+                }
+                match decode_content(&mut response.headers, body, &self.decompression_limits,
+                                     response.truncated) {
+                    Ok((body, truncated)) => {
+                        response.decoded_size = body.len();
+                        response.body = Some(body);
+                        response.truncated = truncated;
+                    },
+                    Err(e) => info!("Can not decode body of {}: {}", self.request.url, e),
+                }
+            }
+            if self.sniff_media_type {
+                if let Some(ref body) = response.body {
+                    response.media_type = Some(sniff(body).to_owned());
+                }
+                let allowed = response.media_type.as_ref().map_or(false, |media_type| {
+                    is_allowed(&self.download_content_types, media_type)
+                });
+                if !allowed {
+                    debug!("Dropping body of {}: sniffed media type {:?} is not downloaded",
+                           self.request.url, response.media_type);
+                    response.body = None;
+                    response.raw_body = None;
+                }
+            }
+            response
+        });
+        self.sender.send((self.request.clone(), response)).unwrap();
+    }
+
+    fn is_truncated(&self) -> bool {
+        self.response.as_ref().map_or(false, |response| response.truncated)
+    }
+
+    /// Store response status and headers, and decide if the body should be read.
+    fn start_response(&mut self, version: &HttpVersion, status: &StatusCode, headers: &Headers)
+                      -> bool {
+        let media_type = media_type(headers);
+        self.response = Some(Response {
+            version: version.clone(),
+            status: status.clone(),
+            headers: headers.clone(),
+            raw_headers: headers.clone(),
+            body: None,
+            raw_body: None,
+            fetched_at: SystemTime::now(),
+            download_time: self.started.elapsed(),
+            wire_size: 0,
+            decoded_size: 0,
+            truncated: false,
+            media_type: media_type.clone(),
+        });
+        if *status != StatusCode::Ok {
+            return false;
+        }
+        // Bodies without a meaningful Content-Type are read to sniff the media type
+        self.sniff_media_type = !self.request.is_robots_txt &&
+            is_generic(media_type.as_ref().map(|media_type| media_type.as_str()));
+        let download = self.request.is_robots_txt || self.sniff_media_type ||
+            media_type.as_ref().map_or(false, |media_type| {
+                is_allowed(&self.download_content_types, media_type)
+            });
+        if download {
+            if let Some(&ContentLength(length)) = headers.get::<ContentLength>() {
+                if length > self.max_body_size as u64 {
+                    info!("Not reading body of {}: Content-Length {} is over the limit",
+                          self.request.url, length);
+                    if let Some(ref mut response) = self.response {
+                        response.truncated = true;
+                    }
+                    return false;
+                }
+            }
+        }
+        download
+    }
+
+    /// Sniff media type as soon as enough of the body is read, dropping the body if this
+    /// type is not downloaded. Returns false if the rest of the body should not be read.
+    /// Encoded bodies are sniffed after decoding, in send_result.
+    fn sniff_start(&mut self) -> bool {
+        if !self.sniff_media_type {
+            return true;
+        }
+        let media_type = match self.response {
+            Some(ref response) if !is_encoded(&response.headers) => match response.body {
+                Some(ref body) if body.len() >= SNIFF_BYTES => sniff(body),
+                _ => return true,
+            },
+            _ => return true,
+        };
+        self.sniff_media_type = false;
+        let allowed = is_allowed(&self.download_content_types, media_type);
+        if let Some(ref mut response) = self.response {
+            response.media_type = Some(media_type.to_owned());
+            if !allowed {
+                response.body = None;
+            }
+        }
+        allowed
+    }
+
+    /// Append available body data, up to max_body_size.
+    fn read_body<R: Read>(&mut self, reader: &mut R) -> io::Result<u64> {
+        let max_body_size = self.max_body_size;
+        let response = self.response.as_mut().expect("Body is read before response");
+        let mut body = response.body.take().unwrap_or(Vec::new());
+        // Read at most one byte over the limit to know that it was exceeded
+        let limit = max_body_size.saturating_add(1).saturating_sub(body.len());
+        let read_result = io::copy(&mut reader.take(limit as u64), &mut body);
+        if body.len() > max_body_size {
+            body.truncate(max_body_size);
+            response.truncated = true;
+        }
+        response.body = Some(body);
+        read_result
     }
 }
 
-impl hyper::client::Handler<HttpStream> for Handler {
+impl<T: Transport> hyper::client::Handler<T> for Handler {
     fn on_request(&mut self, req: &mut HyperRequest) -> Next {
         let mut headers = req.headers_mut();
-        headers.set(Connection::close());
+        if !self.keep_alive {
+            headers.set(Connection::close());
+        }
         headers.set(UserAgent(self.user_agent.clone()));
+        headers.set(AcceptEncoding(accepted_encodings().into_iter().map(qitem).collect()));
         self.read()
     }
 
-    fn on_request_writable(&mut self, _encoder: &mut Encoder<HttpStream>) -> Next {
+    fn on_request_writable(&mut self, _encoder: &mut Encoder<T>) -> Next {
         self.read()
     }
 
@@ -82,48 +262,34 @@ impl hyper::client::Handler<HttpStream> for Handler {
         let status = response.status();
         let headers = response.headers();
         debug!("Got {} for {}", status, self.request.url);
-        self.response = Some(Response {
-            status: status.clone(),
-            headers: headers.clone(),
-            body: None
-        });
-        match status {
-            &StatusCode::Ok => {
-                if is_html(headers) {
-                    self.read()
-                } else {
-                    self.return_response()
-                }
-            },
-            _ => self.return_response()
+        if self.start_response(response.version(), status, headers) {
+            self.read()
+        } else {
+            self.return_response()
         }
     }
 
-    fn on_response_readable(&mut self, decoder: &mut Decoder<HttpStream>) -> Next {
-        let mut read_result = None;
-        if let Some(ref mut response) = self.response {
-            if response.body.is_none() {
-                response.body = Some(Vec::new());
-            }
-            if let Some(ref mut body) = response.body {
-                // TODO - check that this really appends data, not overrides
-                read_result = Some(io::copy(decoder, body));
-            }
+    fn on_response_readable(&mut self, decoder: &mut Decoder<T>) -> Next {
+        let read_result = self.read_body(decoder);
+        if self.is_truncated() {
+            info!("Body of {} truncated at {} bytes", self.request.url, self.max_body_size);
+            return self.return_response();
         }
-        if let Some(read_result) = read_result {
-            match read_result {
-                Ok(0) => self.return_response(),
-                Ok(_) => self.read(),
-                Err(e) => match e.kind() {
-                    io::ErrorKind::WouldBlock => Next::read(),
-                    _ => {
-                        info!("Response read error for {}: {}", self.request.url, e);
-                        self.return_response()
-                    }
+        if !self.sniff_start() {
+            debug!("Not reading body of {}: sniffed media type {:?} is not downloaded",
+                   self.request.url, self.response.as_ref().and_then(|r| r.media_type.clone()));
+            return self.return_response();
+        }
+        match read_result {
+            Ok(0) => self.return_response(),
+            Ok(_) => self.read(),
+            Err(e) => match e.kind() {
+                io::ErrorKind::WouldBlock => Next::read(),
+                _ => {
+                    info!("Response read error for {}: {}", self.request.url, e);
+                    self.return_response()
                 }
             }
-        } else {
-            panic!();
         }
     }
 
@@ -133,3 +299,138 @@ impl hyper::client::Handler<HttpStream> for Handler {
         Next::remove()
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+    use std::sync::{Arc, Mutex, mpsc};
+
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use hyper::header::{ContentEncoding, ContentLength, ContentType, Encoding, Headers};
+    use hyper::status::StatusCode;
+    use hyper::version::HttpVersion;
+
+    use connection_pool::ConnectionPool;
+    use media_type::SNIFF_BYTES;
+    use request::Request;
+    use response::Response;
+    use settings::Settings;
+    use super::*;
+
+    fn new_handler(max_body_size: usize)
+                   -> (Handler, mpsc::Receiver<(Request, Option<Response>)>) {
+        let mut settings = Settings::default();
+        settings.max_body_size = max_body_size;
+        let (tx, rx) = mpsc::channel();
+        let connections = Arc::new(Mutex::new(ConnectionPool::new(1)));
+        (Handler::new(Request::from_str("http://a.com/"), tx, &settings, connections), rx)
+    }
+
+    fn html_headers() -> Headers {
+        let mut headers = Headers::new();
+        headers.set(ContentType("text/html".parse().unwrap()));
+        headers
+    }
+
+    #[test]
+    fn test_content_length_abort() {
+        let (mut handler, rx) = new_handler(1000);
+        let mut headers = html_headers();
+        headers.set(ContentLength(1001));
+        assert!(!handler.start_response(&HttpVersion::Http11, &StatusCode::Ok, &headers));
+        handler.send_result();
+        let response = rx.recv().unwrap().1.unwrap();
+        assert!(response.truncated);
+        assert!(response.body.is_none());
+
+        let (mut handler, _) = new_handler(1000);
+        headers.set(ContentLength(1000));
+        assert!(handler.start_response(&HttpVersion::Http11, &StatusCode::Ok, &headers));
+        assert!(!handler.is_truncated());
+    }
+
+    #[test]
+    fn test_sniff_start() {
+        let binary = vec![0u8; 600];
+        let (mut handler, rx) = new_handler(10000);
+        assert!(handler.start_response(&HttpVersion::Http11, &StatusCode::Ok, &Headers::new()));
+        handler.read_body(&mut Cursor::new(&binary[..100])).unwrap();
+        // Not enough data to sniff yet
+        assert!(handler.sniff_start());
+        handler.read_body(&mut Cursor::new(&binary[100..])).unwrap();
+        assert!(!handler.sniff_start());
+        handler.send_result();
+        let response = rx.recv().unwrap().1.unwrap();
+        assert_eq!(response.media_type, Some("application/octet-stream".to_owned()));
+        assert!(response.body.is_none());
+
+        let html = format!("<html>{}</html>", vec!["<p>a</p>"; 100].join(""));
+        let (mut handler, rx) = new_handler(10000);
+        assert!(handler.start_response(&HttpVersion::Http11, &StatusCode::Ok, &Headers::new()));
+        handler.read_body(&mut Cursor::new(html.as_bytes())).unwrap();
+        assert!(handler.sniff_start());
+        handler.send_result();
+        let response = rx.recv().unwrap().1.unwrap();
+        assert_eq!(response.media_type, Some("text/html".to_owned()));
+        assert_eq!(response.body.unwrap(), html.as_bytes());
+
+        // Compressed body is sniffed only after decoding
+        let mut state = 1u32;
+        let random: Vec<u8> = (0..2000).map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 16) as u8
+        }).collect();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::Default);
+        encoder.write_all(&random).unwrap();
+        let gzip = encoder.finish().unwrap();
+        assert!(gzip.len() >= SNIFF_BYTES);
+        let (mut handler, rx) = new_handler(10000);
+        let mut headers = Headers::new();
+        headers.set(ContentEncoding(vec![Encoding::Gzip]));
+        assert!(handler.start_response(&HttpVersion::Http11, &StatusCode::Ok, &headers));
+        handler.read_body(&mut Cursor::new(&gzip)).unwrap();
+        assert!(handler.sniff_start());
+        handler.send_result();
+        let response = rx.recv().unwrap().1.unwrap();
+        assert_eq!(response.media_type, Some("application/octet-stream".to_owned()));
+        assert!(response.body.is_none());
+    }
+
+    #[test]
+    fn test_truncation() {
+        let data: Vec<u8> = (0..20000).map(|i| i.to_string()).collect::<Vec<_>>()
+            .join(" ").into_bytes();
+        let (mut handler, rx) = new_handler(1000);
+        assert!(handler.start_response(&HttpVersion::Http11, &StatusCode::Ok, &html_headers()));
+        handler.read_body(&mut Cursor::new(&data)).unwrap();
+        assert!(handler.is_truncated());
+        handler.send_result();
+        let response = rx.recv().unwrap().1.unwrap();
+        assert!(response.truncated);
+        assert_eq!(response.body.unwrap(), &data[..1000]);
+
+        // Compressed body is cut on the wire, and decoded as far as it goes
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::Default);
+        encoder.write_all(&data).unwrap();
+        let gzip = encoder.finish().unwrap();
+        assert!(gzip.len() > 20000);
+        let (mut handler, rx) = new_handler(20000);
+        let mut headers = html_headers();
+        headers.set(ContentEncoding(vec![Encoding::Gzip]));
+        assert!(handler.start_response(&HttpVersion::Http11, &StatusCode::Ok, &headers));
+        handler.read_body(&mut Cursor::new(&gzip)).unwrap();
+        assert!(handler.is_truncated());
+        handler.send_result();
+        let response = rx.recv().unwrap().1.unwrap();
+        assert!(response.truncated);
+        assert_eq!(response.wire_size, 20000);
+        assert_eq!(response.decoded_size, 20000);
+        assert_eq!(response.body.unwrap(), &data[..20000]);
+        // Body and headers as received are kept
+        assert_eq!(response.raw_body.unwrap(), &gzip[..20000]);
+        assert!(response.headers.get::<ContentEncoding>().is_none());
+        assert!(response.raw_headers.get::<ContentEncoding>().is_some());
+    }
+}
@@ -0,0 +1,368 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher, SipHasher};
+use std::io::{self, BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::PathBuf;
+
+use request::Request;
+
+
+/// Where queued requests are stored.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrontierStorage {
+    /// All requests are kept in memory
+    Memory,
+    /// Only head_size requests from the start and from the end of each domain queue
+    /// are kept in memory, the rest are appended to a file in path directory.
+    Disk { path: String, head_size: usize },
+}
+
+/// FIFO queue of requests for one domain.
+pub trait DomainFrontier {
+    fn push_back(&mut self, request: Request);
+    /// On error the unreadable part of the queue is dropped, and the request
+    /// that was popped is kept in front, so the next call returns it.
+    fn pop_front(&mut self) -> io::Result<Option<Request>>;
+    fn front(&self) -> Option<&Request>;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn retain(&mut self, f: &Fn(&Request) -> bool) -> io::Result<()>;
+    /// All requests in the queue, in order.
+    fn requests(&self) -> io::Result<Vec<Request>>;
+}
+
+pub fn new_domain_frontier(storage: &FrontierStorage, domain: &str) -> Box<DomainFrontier> {
+    match *storage {
+        FrontierStorage::Memory => Box::new(VecDeque::new()),
+        FrontierStorage::Disk { ref path, head_size } =>
+            Box::new(DiskDomainFrontier::new(path, domain, head_size)),
+    }
+}
+
+impl DomainFrontier for VecDeque<Request> {
+    fn push_back(&mut self, request: Request) {
+        VecDeque::push_back(self, request)
+    }
+
+    fn pop_front(&mut self) -> io::Result<Option<Request>> {
+        Ok(VecDeque::pop_front(self))
+    }
+
+    fn front(&self) -> Option<&Request> {
+        VecDeque::front(self)
+    }
+
+    fn len(&self) -> usize {
+        VecDeque::len(self)
+    }
+
+    fn retain(&mut self, f: &Fn(&Request) -> bool) -> io::Result<()> {
+        VecDeque::retain(self, |request| f(request));
+        Ok(())
+    }
+
+    fn requests(&self) -> io::Result<Vec<Request>> {
+        Ok(self.iter().cloned().collect())
+    }
+}
+
+/// Domain queue that keeps the head and the tail in memory, and everything in between
+/// in an append-only file, one JSON request per line.
+pub struct DiskDomainFrontier {
+    head: VecDeque<Request>,
+    tail: Vec<Request>,
+    head_size: usize,
+    path: PathBuf,
+    // Number of requests in the file after read_offset
+    n_spilled: usize,
+    read_offset: u64,
+}
+
+impl DiskDomainFrontier {
+    pub fn new(dir: &str, domain: &str, head_size: usize) -> Self {
+        // Files are spread between buckets to avoid huge directories
+        let mut hasher = SipHasher::new();
+        domain.hash(&mut hasher);
+        let hash = hasher.finish();
+        let path = PathBuf::from(dir)
+            .join(format!("{:02x}", hash >> 56))
+            .join(format!("{:016x}.jl", hash));
+        DiskDomainFrontier {
+            head: VecDeque::new(),
+            tail: Vec::new(),
+            head_size: if head_size > 0 { head_size } else { 1 },
+            path: path,
+            n_spilled: 0,
+            read_offset: 0,
+        }
+    }
+
+    /// Append tail to the file. The file is truncated if it holds no requests yet,
+    /// so that leftovers of a previous run are not mixed in. On error the tail is kept
+    /// in memory and the file is left as it was.
+    fn flush_tail(&mut self) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            try!(fs::create_dir_all(parent));
+        }
+        let is_new = self.n_spilled == 0 && self.read_offset == 0;
+        let file = try!(OpenOptions::new().create(true).write(true)
+            .append(!is_new).truncate(is_new).open(&self.path));
+        let length = try!(file.metadata()).len();
+        if let Err(e) = write_requests(&file, &self.tail) {
+            try!(file.set_len(length));
+            return Err(e);
+        }
+        self.n_spilled += self.tail.len();
+        self.tail.clear();
+        Ok(())
+    }
+
+    /// Read up to limit spilled requests, advancing read_offset.
+    /// On error the rest of the file is dropped.
+    fn read_spilled(&mut self, limit: usize) -> io::Result<Vec<Request>> {
+        let mut requests = Vec::new();
+        if self.n_spilled == 0 {
+            return Ok(requests);
+        }
+        let result = self.read_spilled_into(limit, &mut requests);
+        if result.is_err() || self.n_spilled == 0 {
+            self.remove_file();
+        }
+        result.map(|()| requests)
+    }
+
+    fn read_spilled_into(&mut self, limit: usize, requests: &mut Vec<Request>)
+            -> io::Result<()> {
+        let mut file = try!(File::open(&self.path));
+        try!(file.seek(SeekFrom::Start(self.read_offset)));
+        let mut reader = BufReader::new(file);
+        let mut line = String::new();
+        while requests.len() < limit && self.n_spilled > 0 {
+            line.clear();
+            let n_read = try!(reader.read_line(&mut line));
+            if n_read == 0 {
+                error!("Frontier file {} is truncated", self.path.display());
+                self.n_spilled = 0;
+                break;
+            }
+            self.read_offset += n_read as u64;
+            self.n_spilled -= 1;
+            match Request::from_json(line.trim_right()) {
+                Some(request) => requests.push(request),
+                None => error!("Error parsing frontier record \"{}\"", line.trim_right()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Move spilled requests (or the tail if there are none) into the empty head.
+    /// The tail is moved even if reading spilled requests fails.
+    fn refill_head(&mut self) -> io::Result<()> {
+        let head_size = self.head_size;
+        let result = self.read_spilled(head_size).map(|requests| self.head.extend(requests));
+        if self.head.is_empty() {
+            self.head.extend(self.tail.drain(..));
+        }
+        result
+    }
+
+    fn remove_file(&mut self) {
+        if self.read_offset > 0 || self.n_spilled > 0 {
+            if let Err(e) = fs::remove_file(&self.path) {
+                error!("Error removing frontier file {}: {}", self.path.display(), e);
+            }
+        }
+        self.n_spilled = 0;
+        self.read_offset = 0;
+    }
+
+    fn read_all_spilled(&self) -> io::Result<Vec<Request>> {
+        let mut requests = Vec::new();
+        if self.n_spilled == 0 {
+            return Ok(requests);
+        }
+        let mut file = try!(File::open(&self.path));
+        try!(file.seek(SeekFrom::Start(self.read_offset)));
+        for line in BufReader::new(file).lines() {
+            if let Some(request) = Request::from_json(&try!(line)) {
+                requests.push(request);
+            }
+        }
+        Ok(requests)
+    }
+}
+
+fn write_requests(file: &File, requests: &[Request]) -> io::Result<()> {
+    let mut writer = BufWriter::new(file);
+    for request in requests {
+        try!(write!(writer, "{}\n", request.to_json()));
+    }
+    writer.flush()
+}
+
+impl DomainFrontier for DiskDomainFrontier {
+    fn push_back(&mut self, request: Request) {
+        if self.n_spilled == 0 && self.tail.is_empty() && self.head.len() < self.head_size {
+            self.head.push_back(request);
+        } else {
+            self.tail.push(request);
+            if self.tail.len() >= self.head_size {
+                if let Err(e) = self.flush_tail() {
+                    error!("Error writing frontier file {}, keeping {} requests in memory: {}",
+                           self.path.display(), self.tail.len(), e);
+                }
+            }
+        }
+    }
+
+    fn pop_front(&mut self) -> io::Result<Option<Request>> {
+        let request = self.head.pop_front();
+        if self.head.is_empty() {
+            if let Err(e) = self.refill_head() {
+                if let Some(request) = request {
+                    self.head.push_front(request);
+                }
+                return Err(e);
+            }
+        }
+        Ok(request)
+    }
+
+    fn front(&self) -> Option<&Request> {
+        self.head.front()
+    }
+
+    fn len(&self) -> usize {
+        self.head.len() + self.n_spilled + self.tail.len()
+    }
+
+    fn retain(&mut self, f: &Fn(&Request) -> bool) -> io::Result<()> {
+        self.head.retain(|request| f(request));
+        self.tail.retain(|request| f(request));
+        let mut result = Ok(());
+        if self.n_spilled > 0 {
+            let spilled = match self.read_all_spilled() {
+                Ok(spilled) => spilled.into_iter().filter(|request| f(request)).collect(),
+                Err(e) => {
+                    result = Err(e);
+                    Vec::new()
+                }
+            };
+            self.remove_file();
+            if !spilled.is_empty() {
+                let tail = mem::replace(&mut self.tail, spilled);
+                if let Err(e) = self.flush_tail() {
+                    error!("Error writing frontier file {}, keeping {} requests in memory: {}",
+                           self.path.display(), self.tail.len(), e);
+                }
+                // Requests that could not be spilled stay in front of the tail
+                self.tail.extend(tail);
+            }
+        }
+        if self.head.is_empty() {
+            let refilled = self.refill_head();
+            if result.is_ok() {
+                result = refilled;
+            }
+        }
+        result
+    }
+
+    fn requests(&self) -> io::Result<Vec<Request>> {
+        let mut requests: Vec<_> = self.head.iter().cloned().collect();
+        requests.extend(try!(self.read_all_spilled()));
+        requests.extend(self.tail.iter().cloned());
+        Ok(requests)
+    }
+}
+
+impl Drop for DiskDomainFrontier {
+    fn drop(&mut self) {
+        self.remove_file();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    use request::Request;
+    use super::*;
+
+    fn urls(requests: Vec<Request>) -> Vec<String> {
+        requests.iter().map(|request| request.url.as_str().to_owned()).collect()
+    }
+
+    #[test]
+    fn test_disk_frontier() {
+        let dir = env::temp_dir().join("crawler-test-disk-frontier");
+        let mut frontier = DiskDomainFrontier::new(dir.to_str().unwrap(), "a.com", 2);
+        let expected: Vec<String> = (0..9).map(|i| format!("http://a.com/{}", i)).collect();
+        for url in &expected {
+            frontier.push_back(Request::from_str(url));
+        }
+        assert_eq!(frontier.len(), 9);
+        assert!(frontier.path.exists());
+        assert_eq!(urls(frontier.requests().unwrap()), expected);
+        frontier.retain(&|request: &Request| request.url.path() != "/3").unwrap();
+        assert_eq!(frontier.len(), 8);
+        let mut popped = Vec::new();
+        while let Some(request) = frontier.pop_front().unwrap() {
+            popped.push(request);
+            if popped.len() == 4 {
+                frontier.push_back(Request::from_str("http://a.com/9"));
+            }
+        }
+        assert!(frontier.is_empty());
+        assert!(!frontier.path.exists());
+        let mut expected = expected;
+        expected.remove(3);
+        expected.push("http://a.com/9".to_owned());
+        assert_eq!(urls(popped), expected);
+    }
+
+    #[test]
+    fn test_leftover_file() {
+        let dir = env::temp_dir().join("crawler-test-leftover-frontier");
+        let mut frontier = DiskDomainFrontier::new(dir.to_str().unwrap(), "a.com", 1);
+        // File left by a crashed run
+        fs::create_dir_all(frontier.path.parent().unwrap()).unwrap();
+        let old_request = Request::from_str("http://a.com/old").to_json();
+        File::create(&frontier.path).unwrap()
+            .write_all(format!("{}\n", old_request).as_bytes()).unwrap();
+        let expected: Vec<String> = (0..3).map(|i| format!("http://a.com/{}", i)).collect();
+        for url in &expected {
+            frontier.push_back(Request::from_str(url));
+        }
+        assert_eq!(urls(frontier.requests().unwrap()), expected);
+        let mut popped = Vec::new();
+        while let Some(request) = frontier.pop_front().unwrap() {
+            popped.push(request);
+        }
+        assert_eq!(urls(popped), expected);
+    }
+
+    #[test]
+    fn test_deleted_file() {
+        let dir = env::temp_dir().join("crawler-test-deleted-frontier");
+        let mut frontier = DiskDomainFrontier::new(dir.to_str().unwrap(), "a.com", 2);
+        for i in 0..5 {
+            frontier.push_back(Request::from_str(&format!("http://a.com/{}", i)));
+        }
+        fs::remove_file(&frontier.path).unwrap();
+        assert!(frontier.requests().is_err());
+        assert_eq!(frontier.pop_front().unwrap().unwrap().url.path(), "/0");
+        // Spilled requests are lost, but the popped request and the tail are kept
+        assert!(frontier.pop_front().is_err());
+        assert_eq!(frontier.len(), 2);
+        assert_eq!(frontier.pop_front().unwrap().unwrap().url.path(), "/1");
+        assert_eq!(frontier.pop_front().unwrap().unwrap().url.path(), "/4");
+        assert_eq!(frontier.pop_front().unwrap(), None);
+    }
+}
@@ -1,23 +1,67 @@
 #![deny(warnings)]
 #[macro_use] extern crate log;
+extern crate base32;
+extern crate brotli2;
 extern crate csv;
+extern crate encoding;
+extern crate flate2;
 extern crate html5ever;
 extern crate hyper;
 extern crate mime;
+extern crate rand;
+extern crate rotor;
+extern crate rusqlite;
 extern crate rustc_serialize;
+extern crate sha1;
 extern crate url;
+extern crate vecio;
+extern crate zstd;
 
+mod canonicalize;
+mod checkpoint;
+mod connection_pool;
+mod content_encoding;
 mod crawl;
+mod decoding;
+mod dedupe;
 mod downloader;
+mod frontier;
 mod link_extraction;
+mod link_graph;
+mod media_type;
+mod microdata;
+mod mirror;
+mod near_dup;
+mod output;
+mod proxy;
 mod queue;
 mod request;
 mod response;
+mod result;
+mod robots;
+mod scope;
 mod settings;
+mod sinks;
+mod sqlite;
 mod stats;
+mod warc;
 
 // Re-exports
-pub use crawl::crawl;
-pub use request::Request;
+pub use canonicalize::{CanonicalizeOptions, canonicalize_url};
+pub use crawl::{crawl, crawl_with_sinks, resume, resume_with_sinks};
+pub use dedupe::DedupeStorage;
+pub use frontier::FrontierStorage;
+pub use request::{Request, Redirect};
 pub use hyper::Url;
+pub use link_graph::LinkGraphWriter;
+pub use microdata::{MicrodataItem, MicrodataValue};
+pub use mirror::MirrorWriter;
+pub use output::{OutputCompression, OutputRotation, RotatingWriter};
+pub use proxy::ProxyAssignment;
+pub use response::Response;
+pub use result::{CrawlResult, LinkRecord, RedirectRecord, ResultFields, ResultHeaders};
+pub use scope::Scope;
 pub use settings::Settings;
+pub use sinks::{Sink, JsonLinesWriter, ResponseLogWriter};
+pub use sqlite::SqliteWriter;
+pub use warc::WarcWriter;
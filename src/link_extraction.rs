@@ -1,48 +1,264 @@
-use html5ever::tokenizer::{TokenSink, Token, Tokenizer, TokenizerOpts};
+use html5ever::tokenizer::{Tag, TagKind, TokenSink, Token, Tokenizer, TokenizerOpts, states};
 use html5ever::tendril::{StrTendril};
 use hyper::Url;
+use rustc_serialize::json::Json;
 
+use microdata::{MicrodataItem, MicrodataParser, get_attr};
 
-struct LinkExtractor {
-    links: Vec<StrTendril>
+
+/// Link found on the page.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Link {
+    pub url: Url,
+    /// Anchor text, with whitespace collapsed
+    pub text: String,
+}
+
+/// Links and text content of the page.
+#[derive(Debug, Default)]
+pub struct ExtractedPage {
+    pub links: Vec<Link>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub keywords: Option<String>,
+    /// lang attribute of the html tag
+    pub lang: Option<String>,
+    /// Text of h1 - h6 tags
+    pub headings: Vec<String>,
+    /// Visible text, without scripts and styles, with whitespace collapsed
+    pub text: String,
+    /// Parsed application/ld+json scripts
+    pub json_ld: Vec<Json>,
+    /// Parse errors of malformed application/ld+json scripts
+    pub json_ld_errors: Vec<String>,
+    /// og:* meta properties
+    pub opengraph: Vec<(String, String)>,
+    /// twitter:* meta tags
+    pub twitter: Vec<(String, String)>,
+    pub microdata: Vec<MicrodataItem>,
 }
 
-impl TokenSink for LinkExtractor {
+struct PageExtractor {
+    /// Link href and anchor text
+    links: Vec<(StrTendril, String)>,
+    in_anchor: bool,
+    title: Option<String>,
+    description: Option<String>,
+    keywords: Option<String>,
+    lang: Option<String>,
+    headings: Vec<String>,
+    text: String,
+    in_script: bool,
+    in_title: bool,
+    heading: Option<String>,
+    /// Content of application/ld+json script being read
+    json_ld_script: Option<String>,
+    json_ld: Vec<Json>,
+    json_ld_errors: Vec<String>,
+    opengraph: Vec<(String, String)>,
+    twitter: Vec<(String, String)>,
+    microdata: MicrodataParser,
+    /// Raw text elements switch tokenizer state, like the tree builder does
+    state_change: Option<states::State>,
+}
+
+impl PageExtractor {
+    fn process_tag(&mut self, tag: Tag) {
+        let is_start = tag.kind == TagKind::StartTag;
+        if is_start {
+            self.microdata.start_tag(&tag);
+            let raw_kind = match &*tag.name {
+                "script" => Some(states::RawKind::ScriptData),
+                "style" | "xmp" | "iframe" | "noembed" | "noframes" =>
+                    Some(states::RawKind::Rawtext),
+                "title" | "textarea" => Some(states::RawKind::Rcdata),
+                _ => None,
+            };
+            self.state_change = raw_kind.map(states::State::RawData);
+        } else {
+            self.microdata.end_tag(&tag);
+        }
+        match &*tag.name {
+            "a" => {
+                self.in_anchor = false;
+                if is_start {
+                    for attr in tag.attrs {
+                        if attr.name.local.eq_str_ignore_ascii_case("href") {
+                            self.links.push((attr.value, String::new()));
+                            self.in_anchor = true;
+                        }
+                    }
+                }
+            },
+            "html" => if is_start && self.lang.is_none() {
+                self.lang = get_attr(&tag, "lang");
+            },
+            "meta" => {
+                let name = get_attr(&tag, "name").or_else(|| get_attr(&tag, "property"))
+                    .map(|name| name.to_lowercase());
+                let content = get_attr(&tag, "content");
+                if let (Some(name), Some(content)) = (name, content) {
+                    if name == "description" && self.description.is_none() {
+                        self.description = Some(collapse_whitespace(&content));
+                    } else if name == "keywords" && self.keywords.is_none() {
+                        self.keywords = Some(collapse_whitespace(&content));
+                    } else if name.starts_with("og:") {
+                        self.opengraph.push((name, content));
+                    } else if name.starts_with("twitter:") {
+                        self.twitter.push((name, content));
+                    }
+                }
+            },
+            "script" => {
+                self.in_script = is_start;
+                if is_start {
+                    let is_json_ld = get_attr(&tag, "type").map_or(false, |script_type|
+                        script_type.trim().to_lowercase() == "application/ld+json");
+                    if is_json_ld {
+                        self.json_ld_script = Some(String::new());
+                    }
+                } else if let Some(script) = self.json_ld_script.take() {
+                    match Json::from_str(&script) {
+                        Ok(json) => self.json_ld.push(json),
+                        Err(e) => self.json_ld_errors.push(e.to_string()),
+                    }
+                }
+            },
+            "style" => self.in_script = is_start,
+            "title" => {
+                self.in_title = is_start && self.title.is_none();
+                if self.in_title {
+                    self.title = Some(String::new());
+                }
+            },
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                if is_start {
+                    self.heading = Some(String::new());
+                } else if let Some(heading) = self.heading.take() {
+                    let heading = collapse_whitespace(&heading);
+                    if !heading.is_empty() {
+                        self.headings.push(heading);
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+impl TokenSink for PageExtractor {
     fn process_token(&mut self, token: Token) {
-        if let Token::TagToken(tag) = token {
-            if tag.name.eq_str_ignore_ascii_case("a") {
-                for attr in tag.attrs {
-                    if attr.name.local.eq_str_ignore_ascii_case("href") {
-                        self.links.push(attr.value);
+        match token {
+            Token::TagToken(tag) => {
+                self.process_tag(tag);
+                // Tags separate words
+                self.text.push(' ');
+                if let Some(ref mut heading) = self.heading {
+                    heading.push(' ');
+                }
+                if self.in_anchor {
+                    if let Some(&mut (_, ref mut anchor)) = self.links.last_mut() {
+                        anchor.push(' ');
                     }
                 }
-            }
+            },
+            Token::CharacterTokens(text) => {
+                if self.in_script {
+                    if let Some(ref mut script) = self.json_ld_script {
+                        script.push_str(&text);
+                    }
+                    return;
+                }
+                self.microdata.text(&text);
+                if self.in_title {
+                    if let Some(ref mut title) = self.title {
+                        title.push_str(&text);
+                    }
+                    return;
+                }
+                self.text.push_str(&text);
+                if let Some(ref mut heading) = self.heading {
+                    heading.push_str(&text);
+                }
+                if self.in_anchor {
+                    if let Some(&mut (_, ref mut anchor)) = self.links.last_mut() {
+                        anchor.push_str(&text);
+                    }
+                }
+            },
+            _ => (),
         }
     }
+
+    fn query_state_change(&mut self) -> Option<states::State> {
+        self.state_change.take()
+    }
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
 }
 
-pub fn extract_links(body: &str, base_url: &Url) -> Vec<Url> {
-    let mut tokenizer = Tokenizer::new(
-        LinkExtractor{links: Vec::new()}, TokenizerOpts::default());
+/// Extract links, metadata and text in a single pass of the tokenizer.
+pub fn extract_page(body: &str, base_url: &Url) -> ExtractedPage {
+    let mut tokenizer = Tokenizer::new(PageExtractor {
+        links: Vec::new(),
+        in_anchor: false,
+        title: None,
+        description: None,
+        keywords: None,
+        lang: None,
+        headings: Vec::new(),
+        text: String::new(),
+        in_script: false,
+        in_title: false,
+        heading: None,
+        json_ld_script: None,
+        json_ld: Vec::new(),
+        json_ld_errors: Vec::new(),
+        opengraph: Vec::new(),
+        twitter: Vec::new(),
+        microdata: MicrodataParser::new(base_url),
+        state_change: None,
+    }, TokenizerOpts::default());
     tokenizer.feed(StrTendril::from(body));
     tokenizer.end();
-    let link_extractor = tokenizer.unwrap();
-    link_extractor.links.iter().filter_map(|href| {
+    let extractor = tokenizer.unwrap();
+    let links = extractor.links.iter().filter_map(|&(ref href, ref text)| {
         if let Ok(url) = base_url.join(href) {
             let supported_scheme = {
                 let scheme = url.scheme();
                 scheme == "http" || scheme == "https"
             };
-            if supported_scheme { Some(url) } else { None }
+            if supported_scheme {
+                Some(Link { url: url, text: collapse_whitespace(text) })
+            } else {
+                None
+            }
         } else {
             None
         }
-    }).collect()
+    }).collect();
+    ExtractedPage {
+        links: links,
+        title: extractor.title.map(|title| collapse_whitespace(&title)),
+        description: extractor.description,
+        keywords: extractor.keywords,
+        lang: extractor.lang,
+        headings: extractor.headings,
+        text: collapse_whitespace(&extractor.text),
+        json_ld: extractor.json_ld,
+        json_ld_errors: extractor.json_ld_errors,
+        opengraph: extractor.opengraph,
+        twitter: extractor.twitter,
+        microdata: extractor.microdata.finish(),
+    }
 }
 
 
 #[cfg(test)]
 mod tests {
+    use microdata::{MicrodataItem, MicrodataValue};
     use super::*;
 
     #[test]
@@ -53,8 +269,86 @@ mod tests {
                     <a href=\"javascript:void(0)\"></a>\
                     <a href=\"ftp://foo.com\"></a>\
                     <a href=\"http://example.com/zoo\">a zoo</a>";
-        let links = extract_links(&html, &base_url);
-        assert_eq!(links, vec!["http://foo.com/boo.txt".parse().unwrap(),
-                               "http://example.com/zoo".parse().unwrap()])
+        let links = extract_page(&html, &base_url).links;
+        assert_eq!(links, vec![
+            Link { url: "http://foo.com/boo.txt".parse().unwrap(), text: "a boo".to_owned() },
+            Link { url: "http://example.com/zoo".parse().unwrap(), text: "a zoo".to_owned() }])
+    }
+
+    #[test]
+    fn test_raw_text() {
+        let html = "<head><title>A &amp; <b>B</b></title>\
+                    <script>var a = \"<a href='http://foo.com/b'>b</a>\";</script>\
+                    <style>p::after { content: \"<p>\"; }</style></head>\
+                    <body><p>C &amp; D</p><a href=\"http://foo.com/e\">e</a></body>";
+        let page = extract_page(html, &"http://foo.com".parse().unwrap());
+        assert_eq!(page.title, Some("A & <b>B</b>".to_owned()));
+        assert_eq!(page.text, "C & D e");
+        assert_eq!(page.links, vec![
+            Link { url: "http://foo.com/e".parse().unwrap(), text: "e".to_owned() }]);
+    }
+
+    #[test]
+    fn test_extract_text() {
+        let html = "<html lang=\"en\"><head><title> A\n page </title>\
+                    <style>p { color: red; }</style>\
+                    <meta name=\"Description\" content=\"About  a page\">\
+                    <meta name=\"keywords\" content=\"a, page\"></head>\
+                    <body><h1>Hello <b>there</b></h1><p>Hello<b>world</b></p>\
+                    <script>var a = 1;</script><h2></h2></body></html>";
+        let page = extract_page(html, &"http://foo.com".parse().unwrap());
+        assert_eq!(page.title, Some("A page".to_owned()));
+        assert_eq!(page.description, Some("About a page".to_owned()));
+        assert_eq!(page.keywords, Some("a, page".to_owned()));
+        assert_eq!(page.lang, Some("en".to_owned()));
+        assert_eq!(page.headings, vec!["Hello there"]);
+        assert_eq!(page.text, "Hello there Hello world");
+    }
+
+    #[test]
+    fn test_json_ld_raw_text() {
+        let json_ld = "{\"name\": \"<b>A</b> & B &amp; 1 < 2\"}";
+        let html = format!("<script type=\"application/ld+json\">{}</script>", json_ld);
+        let page = extract_page(&html, &"http://foo.com".parse().unwrap());
+        assert_eq!(page.json_ld_errors.len(), 0);
+        assert_eq!(page.json_ld, vec![Json::from_str(json_ld).unwrap()]);
+        assert_eq!(page.json_ld[0].find("name").unwrap().as_string(),
+                   Some("<b>A</b> & B &amp; 1 < 2"));
+    }
+
+    #[test]
+    fn test_structured_data() {
+        let html = "<head><meta property=\"og:title\" content=\"A title\">\
+                    <meta name=\"twitter:card\" content=\"summary\">\
+                    <script type=\"application/ld+json\">{\"@type\": \"Article\"}</script>\
+                    <script type=\"application/ld+json\">{\"@type\": </script>\
+                    <script>var a = {};</script></head>\
+                    <body><div itemscope itemtype=\"http://schema.org/Product\" itemid=\"/p/1\">\
+                    <span itemprop=\"name\">A <b>thing</b></span>\
+                    <img itemprop=\"image\" src=\"a.jpg\">\
+                    <a itemprop=\"url\" href=\"http://[bad\">a</a>\
+                    <div itemprop=\"offers\" itemscope itemtype=\"http://schema.org/Offer\">\
+                    <meta itemprop=\"price\" content=\"1.5\"></div>\
+                    </div><p itemprop=\"name\">Not an item</p></body>";
+        let page = extract_page(html, &"http://foo.com/a/".parse().unwrap());
+        assert_eq!(page.opengraph, vec![("og:title".to_owned(), "A title".to_owned())]);
+        assert_eq!(page.twitter, vec![("twitter:card".to_owned(), "summary".to_owned())]);
+        assert_eq!(page.json_ld, vec![Json::from_str("{\"@type\": \"Article\"}").unwrap()]);
+        assert_eq!(page.json_ld_errors.len(), 1);
+        let text = |value: &str| MicrodataValue::Text(value.to_owned());
+        assert_eq!(page.microdata, vec![MicrodataItem {
+            types: vec!["http://schema.org/Product".to_owned()],
+            id: Some("http://foo.com/p/1".to_owned()),
+            properties: vec![
+                ("name".to_owned(), text("A thing")),
+                ("image".to_owned(), text("http://foo.com/a/a.jpg")),
+                ("url".to_owned(), text("")),
+                ("offers".to_owned(), MicrodataValue::Item(MicrodataItem {
+                    types: vec!["http://schema.org/Offer".to_owned()],
+                    id: None,
+                    properties: vec![("price".to_owned(), text("1.5"))],
+                })),
+            ],
+        }]);
     }
 }
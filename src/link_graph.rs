@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::Path;
+
+use csv;
+use hyper::Url;
+
+use output::RotatingWriter;
+use result::CrawlResult;
+use sinks::Sink;
+
+
+/// Writes page level link graph edges (source url, target url, anchor text) in CSV format,
+/// and host level edge counts, which are rewritten at checkpoints and at the end of the crawl.
+/// When resuming, counts from an existing host edges file are added to,
+/// otherwise the file is truncated.
+pub struct LinkGraphWriter {
+    edges: Option<RotatingWriter>,
+    host_edges_path: Option<String>,
+    host_edges: HashMap<(String, String), u64>,
+}
+
+impl LinkGraphWriter {
+    pub fn new(edges: Option<RotatingWriter>, host_edges_path: Option<String>, resume: bool)
+            -> Self {
+        let host_edges = match host_edges_path {
+            Some(ref path) if resume && Path::new(path).exists() => read_host_edges(path)
+                .unwrap_or_else(|e| panic!("Failed to read host edges from {}: {}", path, e)),
+            Some(ref path) => {
+                File::create(path)
+                    .unwrap_or_else(|e| panic!("Failed to create {}: {}", path, e));
+                HashMap::new()
+            }
+            None => HashMap::new(),
+        };
+        LinkGraphWriter {
+            edges: edges,
+            host_edges_path: host_edges_path,
+            host_edges: host_edges,
+        }
+    }
+
+    fn write_host_edges(&self) {
+        if let Some(ref path) = self.host_edges_path {
+            let mut host_edges: Vec<_> = self.host_edges.iter().collect();
+            host_edges.sort_by(|a, b| (b.1, a.0).cmp(&(a.1, b.0)));
+            // Write to a temporary file first, so that a complete file is always present
+            let tmp_path = format!("{}.tmp", path);
+            {
+                let mut writer = csv::Writer::from_writer(File::create(&tmp_path).unwrap());
+                for (&(ref source, ref target), count) in host_edges {
+                    writer.encode((source, target, count)).unwrap();
+                }
+                writer.flush().unwrap();
+            }
+            fs::rename(&tmp_path, path).unwrap();
+            info!("Saved {} host edges to {}", self.host_edges.len(), path);
+        }
+    }
+}
+
+fn read_host_edges(path: &str) -> csv::Result<HashMap<(String, String), u64>> {
+    let mut reader = try!(csv::Reader::from_file(path)).has_headers(false);
+    let mut host_edges = HashMap::new();
+    for record in reader.decode() {
+        let (source, target, count): (String, String, u64) = try!(record);
+        host_edges.insert((source, target), count);
+    }
+    Ok(host_edges)
+}
+
+impl Sink for LinkGraphWriter {
+    fn write_result(&mut self, result: &CrawlResult) {
+        let source_host = Url::parse(&result.url).ok()
+            .and_then(|url| url.host_str().map(|host| host.to_owned()));
+        for link in &result.links {
+            if let Some(ref mut edges) = self.edges {
+                let mut record = csv::Writer::from_memory();
+                record.encode((&result.url, &link.url, &link.text)).unwrap();
+                edges.write_record(record.as_bytes());
+            }
+            if self.host_edges_path.is_some() {
+                let target_host = Url::parse(&link.url).ok()
+                    .and_then(|url| url.host_str().map(|host| host.to_owned()));
+                if let (Some(source), Some(target)) = (source_host.clone(), target_host) {
+                    *self.host_edges.entry((source, target)).or_insert(0) += 1;
+                }
+            }
+        }
+    }
+
+    fn checkpoint(&mut self) {
+        if let Some(ref mut edges) = self.edges {
+            edges.flush();
+        }
+        self.write_host_edges();
+    }
+
+    fn close(&mut self) {
+        if let Some(ref mut edges) = self.edges {
+            edges.close();
+        }
+        self.write_host_edges();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Read;
+
+    use output::{OutputCompression, OutputRotation, RotatingWriter};
+    use result::{CrawlResult, LinkRecord};
+    use super::*;
+
+    fn result(url: &str, links: &[&str]) -> CrawlResult {
+        CrawlResult {
+            url: url.to_owned(),
+            original_url: url.to_owned(),
+            status: 200,
+            links: links.iter().map(|link| LinkRecord {
+                url: link.to_string(),
+                text: "a, b".to_owned(),
+            }).collect(),
+            .. CrawlResult::default()
+        }
+    }
+
+    fn read(path: &str) -> String {
+        let mut contents = String::new();
+        File::open(path).unwrap().read_to_string(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn test_link_graph() {
+        let dir = env::temp_dir().join("crawler-test-link-graph");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let edges_path = dir.join("edges.csv").to_str().unwrap().to_owned();
+        let host_edges_path = dir.join("host_edges.csv").to_str().unwrap().to_owned();
+        let new_writer = |resume| LinkGraphWriter::new(
+            Some(RotatingWriter::new(
+                &edges_path, &OutputRotation::default(), OutputCompression::None)),
+            Some(host_edges_path.clone()), resume);
+        let mut writer = new_writer(false);
+        writer.write_result(&result("http://a.com/", &["http://b.com/1", "http://b.com/2"]));
+        writer.checkpoint();
+        assert_eq!(read(&host_edges_path), "a.com,b.com,2\n");
+        writer.write_result(&result("http://b.com/1", &["http://a.com/", "http://b.com/2"]));
+        writer.write_result(&result("http://a.com/x", &["http://b.com/3"]));
+        writer.close();
+        assert_eq!(read(&host_edges_path), "a.com,b.com,3\nb.com,a.com,1\nb.com,b.com,1\n");
+        assert_eq!(read(&edges_path).lines().next(),
+                   Some("http://a.com/,http://b.com/1,\"a, b\""));
+        assert_eq!(read(&edges_path).lines().count(), 5);
+        // Counts are kept when the crawl is resumed
+        let mut writer = new_writer(true);
+        writer.write_result(&result("http://b.com/2", &["http://a.com/"]));
+        writer.close();
+        assert_eq!(read(&host_edges_path), "a.com,b.com,3\nb.com,a.com,2\nb.com,b.com,1\n");
+        assert_eq!(read(&edges_path).lines().count(), 6);
+        // and start from scratch in a new crawl
+        let writer = new_writer(false);
+        assert_eq!(read(&host_edges_path), "");
+        drop(writer);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
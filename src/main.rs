@@ -7,7 +7,7 @@ use std::io::{BufRead, BufReader};
 use std::fs::File;
 use std::str;
 
-use crawler::{crawl, Url, Settings};
+use crawler::{crawl, resume, Url, Settings};
 
 
 pub fn parse_seed(seed: &str) -> Option<Url> {
@@ -23,15 +23,27 @@ pub fn parse_seed(seed: &str) -> Option<Url> {
 fn main() {
     env_logger::init().unwrap();
 
-    let seeds_filename = match env::args().nth(1) {
+    let usage = "Usage: crawler <urls file> [<job dir>] | crawler --resume <job dir>";
+    let mut settings = Settings::default();
+    let mut args = env::args().skip(1);
+    let seeds_filename = match args.next() {
+        Some(ref arg) if arg == "--resume" => {
+            settings.job_dir = args.next();
+            if settings.job_dir.is_some() {
+                resume(&settings);
+            } else {
+                error!("{}", usage);
+            }
+            return;
+        },
         Some(seeds_filename) => seeds_filename,
         None => {
-            error!("Usage: client <urls file>");
+            error!("{}", usage);
             return;
         }
     };
+    settings.job_dir = args.next();
 
-    let settings = Settings::default();
     let seeds_file = BufReader::new(File::open(seeds_filename).unwrap());
     let seeds: Vec<Url> = seeds_file.lines().filter_map(|line| {
         let line = line.unwrap();
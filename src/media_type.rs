@@ -0,0 +1,136 @@
+use std::cmp;
+
+use hyper::header::{ContentType, Headers};
+use mime::Mime;
+
+
+/// Only this many bytes at the start of the body are checked for binary data,
+/// so the type can be sniffed as soon as they are read.
+pub const SNIFF_BYTES: usize = 512;
+
+/// Content-Type values that say nothing about the content, sniffing is used instead.
+const GENERIC_TYPES: &'static [&'static str] = &[
+    "application/octet-stream", "application/unknown", "binary/octet-stream",
+    "unknown/unknown", "*/*"];
+
+/// Signatures of binary formats, checked at the very start of the body.
+const SIGNATURES: &'static [(&'static [u8], &'static str)] = &[
+    (b"%PDF-", "application/pdf"),
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"\x1f\x8b\x08", "application/x-gzip"),
+    (b"PK\x03\x04", "application/zip"),
+];
+
+/// Tags that start an HTML document, checked after leading whitespace.
+const HTML_STARTS: &'static [&'static str] = &[
+    "<!doctype html", "<html", "<head", "<body", "<title", "<script", "<style",
+    "<meta", "<div", "<table", "<iframe", "<h1", "<p", "<a", "<br", "<b", "<font", "<!--"];
+
+/// Media type (like text/html, without parameters) from Content-Type header.
+pub fn media_type(headers: &Headers) -> Option<String> {
+    headers.get::<ContentType>().map(|&ContentType(Mime(ref top, ref sub, _))| {
+        format!("{}/{}", top, sub).to_lowercase()
+    })
+}
+
+/// Media type is missing or does not say anything about the content.
+pub fn is_generic(media_type: Option<&str>) -> bool {
+    media_type.map_or(true, |media_type| GENERIC_TYPES.contains(&media_type))
+}
+
+/// Media types that links and metadata are extracted from.
+pub fn is_html(media_type: &str) -> bool {
+    media_type == "text/html" || media_type == "application/xhtml+xml"
+}
+
+/// Media types with textual content, stored as text rather than base64.
+pub fn is_text(media_type: &str) -> bool {
+    media_type.starts_with("text/") || media_type.ends_with("/xml") ||
+        media_type.ends_with("+xml") || media_type.ends_with("/json") ||
+        media_type.ends_with("+json") || media_type.ends_with("/javascript")
+}
+
+/// Media type matches a pattern like text/html, image/* or */*.
+pub fn matches(pattern: &str, media_type: &str) -> bool {
+    let pattern = pattern.trim().to_lowercase();
+    if pattern == "*/*" || pattern == "*" {
+        true
+    } else if pattern.ends_with("/*") {
+        media_type.starts_with(&pattern[..pattern.len() - 1])
+    } else {
+        pattern == media_type
+    }
+}
+
+/// Media type matches any of the patterns.
+pub fn is_allowed(patterns: &[String], media_type: &str) -> bool {
+    patterns.iter().any(|pattern| matches(pattern, media_type))
+}
+
+/// Guess media type from the start of the body: known binary signatures,
+/// HTML and XML starts, and text vs. binary by control characters.
+pub fn sniff(body: &[u8]) -> &'static str {
+    for &(signature, media_type) in SIGNATURES {
+        if body.starts_with(signature) {
+            return media_type;
+        }
+    }
+    let start = body.iter().position(|&b| !(b as char).is_whitespace()).unwrap_or(body.len());
+    let head = String::from_utf8_lossy(&body[start..cmp::min(body.len(), start + 16)])
+        .to_lowercase();
+    for &html_start in HTML_STARTS {
+        if head.starts_with(html_start) {
+            // Tag name must end here, so that "<pre" is not taken for "<p"
+            let tag_ends = head[html_start.len()..].chars().next()
+                .map_or(true, |c| c == '>' || c == '/' || c.is_whitespace());
+            if html_start.starts_with("<!") || tag_ends {
+                return "text/html";
+            }
+        }
+    }
+    if head.starts_with("<?xml") {
+        return "application/xml";
+    }
+    let is_binary = body[..cmp::min(body.len(), SNIFF_BYTES)].iter().any(|&b| {
+        match b {
+            0x00 ... 0x08 | 0x0b | 0x0e ... 0x1a | 0x1c ... 0x1f => true,
+            _ => false,
+        }
+    });
+    if is_binary { "application/octet-stream" } else { "text/plain" }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(sniff(b"%PDF-1.4\n"), "application/pdf");
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0"), "image/png");
+        assert_eq!(sniff(b"\n  <!DOCTYPE html><html>"), "text/html");
+        assert_eq!(sniff(b"<p>a</p>"), "text/html");
+        assert_eq!(sniff(b"<pre>a</pre>"), "text/plain");
+        assert_eq!(sniff(b"<?xml version=\"1.0\"?><rss>"), "application/xml");
+        assert_eq!(sniff(b"User-agent: *\nDisallow: /"), "text/plain");
+        assert_eq!(sniff(b"\x00\x01\x02binary"), "application/octet-stream");
+        assert_eq!(sniff(b""), "text/plain");
+    }
+
+    #[test]
+    fn test_matches() {
+        let patterns = vec!["text/html".to_owned(), "image/*".to_owned()];
+        assert!(is_allowed(&patterns, "text/html"));
+        assert!(is_allowed(&patterns, "image/png"));
+        assert!(!is_allowed(&patterns, "text/plain"));
+        assert!(!is_allowed(&patterns, "application/pdf"));
+        assert!(matches("*/*", "application/pdf"));
+        assert!(is_generic(None));
+        assert!(is_generic(Some("application/octet-stream")));
+        assert!(!is_generic(Some("text/plain")));
+    }
+}
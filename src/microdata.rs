@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+
+use html5ever::tokenizer::Tag;
+use hyper::Url;
+use rustc_serialize::json::{Json, ToJson};
+
+
+/// Schema.org (or other vocabulary) microdata item.
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub struct MicrodataItem {
+    /// Space separated itemtype values
+    pub types: Vec<String>,
+    /// Absolute itemid url
+    pub id: Option<String>,
+    pub properties: Vec<(String, MicrodataValue)>,
+}
+
+#[derive(Debug, Clone, PartialEq, RustcEncodable)]
+pub enum MicrodataValue {
+    Text(String),
+    Item(MicrodataItem),
+}
+
+/// Items are serialized as in the W3C microdata JSON format:
+/// {"type": [...], "id": "...", "properties": {"name": [values]}}.
+impl ToJson for MicrodataItem {
+    fn to_json(&self) -> Json {
+        let mut properties = BTreeMap::new();
+        for &(ref name, ref value) in &self.properties {
+            let value = match *value {
+                MicrodataValue::Text(ref text) => text.to_json(),
+                MicrodataValue::Item(ref item) => item.to_json(),
+            };
+            if let Json::Array(ref mut values) = *properties.entry(name.clone())
+                    .or_insert_with(|| Json::Array(Vec::new())) {
+                values.push(value);
+            }
+        }
+        let mut obj = BTreeMap::new();
+        obj.insert("type".to_owned(), self.types.to_json());
+        if let Some(ref id) = self.id {
+            obj.insert("id".to_owned(), id.to_json());
+        }
+        obj.insert("properties".to_owned(), Json::Object(properties));
+        Json::Object(obj)
+    }
+}
+
+const VOID_ELEMENTS: &'static [&'static str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param",
+    "source", "track", "wbr"];
+
+struct OpenElement {
+    name: String,
+    /// Property names of the item started by this element
+    item_props: Option<Vec<String>>,
+    /// Property names and text of the element, when value is its text content
+    text_props: Option<(Vec<String>, String)>,
+}
+
+/// Builds microdata items from a stream of tokenizer events.
+pub struct MicrodataParser {
+    /// Url values are resolved against it
+    base_url: Url,
+    elements: Vec<OpenElement>,
+    open_items: Vec<MicrodataItem>,
+    items: Vec<MicrodataItem>,
+}
+
+impl MicrodataParser {
+    pub fn new(base_url: &Url) -> Self {
+        MicrodataParser {
+            base_url: base_url.clone(),
+            elements: Vec::new(),
+            open_items: Vec::new(),
+            items: Vec::new(),
+        }
+    }
+
+    pub fn start_tag(&mut self, tag: &Tag) {
+        let name = tag.name.to_lowercase();
+        let is_void = tag.self_closing || VOID_ELEMENTS.iter().any(|&void| void == name);
+        let props: Vec<String> = get_attr(tag, "itemprop")
+            .map(|props| props.split_whitespace().map(String::from).collect())
+            .unwrap_or_else(Vec::new);
+        let in_item = !self.open_items.is_empty();
+        if get_attr(tag, "itemscope").is_some() {
+            let id = get_attr(tag, "itemid").map(|id| self.resolve(&id));
+            self.open_items.push(MicrodataItem {
+                types: get_attr(tag, "itemtype")
+                    .map(|types| types.split_whitespace().map(String::from).collect())
+                    .unwrap_or_else(Vec::new),
+                id: id,
+                properties: Vec::new(),
+            });
+            let item_props = if in_item { props } else { Vec::new() };
+            if is_void {
+                self.close_item(item_props);
+            } else {
+                self.elements.push(OpenElement {
+                    name: name, item_props: Some(item_props), text_props: None });
+            }
+            return;
+        }
+        let mut text_props = None;
+        if in_item && !props.is_empty() {
+            match self.attr_value(tag, &name) {
+                Some(value) => self.add_property(props, MicrodataValue::Text(value)),
+                None => text_props = Some((props, String::new())),
+            }
+        }
+        if !is_void {
+            self.elements.push(OpenElement {
+                name: name, item_props: None, text_props: text_props });
+        }
+    }
+
+    pub fn end_tag(&mut self, tag: &Tag) {
+        let name = tag.name.to_lowercase();
+        if !self.elements.iter().any(|element| element.name == name) {
+            return;
+        }
+        while let Some(element) = self.elements.pop() {
+            if let Some((props, text)) = element.text_props {
+                let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+                self.add_property(props, MicrodataValue::Text(text));
+            }
+            if let Some(item_props) = element.item_props {
+                self.close_item(item_props);
+            }
+            if element.name == name {
+                break;
+            }
+        }
+    }
+
+    pub fn text(&mut self, text: &str) {
+        for element in &mut self.elements {
+            if let Some((_, ref mut element_text)) = element.text_props {
+                element_text.push_str(text);
+            }
+        }
+    }
+
+    /// Top level items, including ones not closed at the end of the page.
+    pub fn finish(mut self) -> Vec<MicrodataItem> {
+        while let Some(element) = self.elements.pop() {
+            if let Some(item_props) = element.item_props {
+                self.close_item(item_props);
+            }
+        }
+        self.items
+    }
+
+    /// Property value taken from an attribute, None if the value is the text content.
+    fn attr_value(&self, tag: &Tag, name: &str) -> Option<String> {
+        let attr = match name {
+            "meta" => "content",
+            "a" | "area" | "link" => "href",
+            "audio" | "embed" | "iframe" | "img" | "source" | "track" | "video" => "src",
+            "object" => "data",
+            "data" | "meter" => "value",
+            "time" => "datetime",
+            _ => return None,
+        };
+        let value = get_attr(tag, attr);
+        match attr {
+            "href" | "src" | "data" =>
+                Some(value.map_or_else(String::new, |value| self.resolve(&value))),
+            "datetime" => value,
+            _ => value.or_else(|| Some(String::new())),
+        }
+    }
+
+    /// Absolute url, or an empty string if the url is invalid.
+    fn resolve(&self, url: &str) -> String {
+        self.base_url.join(url).map(|url| url.to_string()).unwrap_or_else(|_| String::new())
+    }
+
+    fn close_item(&mut self, props: Vec<String>) {
+        if let Some(item) = self.open_items.pop() {
+            if !props.is_empty() && !self.open_items.is_empty() {
+                self.add_property(props, MicrodataValue::Item(item));
+            } else {
+                self.items.push(item);
+            }
+        }
+    }
+
+    fn add_property(&mut self, props: Vec<String>, value: MicrodataValue) {
+        if let Some(item) = self.open_items.last_mut() {
+            for prop in props {
+                item.properties.push((prop, value.clone()));
+            }
+        }
+    }
+}
+
+pub fn get_attr(tag: &Tag, name: &str) -> Option<String> {
+    tag.attrs.iter()
+        .find(|attr| attr.name.local.eq_str_ignore_ascii_case(name))
+        .map(|attr| attr.value.to_string())
+}
@@ -0,0 +1,457 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher, SipHasher};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::path::{Component, Path, PathBuf};
+
+use encoding::{DecoderTrap, EncoderTrap, EncodingRef};
+use encoding::all::UTF_8;
+use encoding::label::encoding_from_whatwg_label;
+use html5ever::tokenizer::{Tag, TagKind, Token, TokenSink, Tokenizer, TokenizerOpts, states};
+use html5ever::tendril::StrTendril;
+use hyper::status::StatusCode;
+use hyper::Url;
+
+use media_type::is_html;
+use request::Request;
+use response::Response;
+use result::CrawlResult;
+use sinks::Sink;
+
+
+/// File names are limited to this many bytes.
+const MAX_NAME_LENGTH: usize = 200;
+
+/// Tag and attribute names of links that are rewritten.
+const LINK_ATTRIBUTES: &'static [(&'static str, &'static str)] = &[
+    ("a", "href"), ("area", "href"), ("link", "href"), ("img", "src"), ("script", "src"),
+    ("iframe", "src"), ("frame", "src"), ("embed", "src"), ("source", "src")];
+
+/// Saves response bodies into a host/path directory tree, like "wget -m".
+/// Urls are mapped to files with local_path. If rewrite_links is set, links in HTML pages
+/// are converted when the crawl is finished, like "wget -k": links to mirrored urls
+/// are rewritten to relative paths of their local copies, other links are made absolute.
+pub struct MirrorWriter {
+    root: PathBuf,
+    rewrite_links: bool,
+    /// Files written during the crawl, with urls they were written for
+    written: HashMap<PathBuf, String>,
+    /// Local paths of mirrored urls
+    paths: HashMap<String, PathBuf>,
+    /// Urls and encodings of mirrored HTML pages, links in them are rewritten on close
+    pages: Vec<(String, String)>,
+}
+
+impl MirrorWriter {
+    pub fn new(root: &str, rewrite_links: bool) -> Self {
+        MirrorWriter {
+            root: PathBuf::from(root),
+            rewrite_links: rewrite_links,
+            written: HashMap::new(),
+            paths: HashMap::new(),
+            pages: Vec::new(),
+        }
+    }
+
+    fn write_file(&mut self, url: &Url, body: &[u8]) {
+        let mut path = local_path(url);
+        let taken = match self.written.get(&path) {
+            Some(written_url) => written_url != url.as_str(),
+            None => false,
+        };
+        if taken {
+            // Different urls mapped to the same file, e.g. "/a" and "/a.html"
+            path = with_name_suffix(&path, &format!("-{:016x}", hash(url.as_str())));
+        }
+        let full_path = self.root.join(&path);
+        if let Some(parent) = full_path.parent() {
+            if let Err(e) = fs::create_dir_all(parent) {
+                error!("Can not mirror {} to {}: {}", url, full_path.display(), e);
+                return;
+            }
+        }
+        match File::create(&full_path).and_then(|mut file| file.write_all(body)) {
+            Ok(()) => {
+                self.written.insert(path.clone(), url.as_str().to_owned());
+                self.paths.insert(url.as_str().to_owned(), path);
+            },
+            Err(e) => error!("Can not mirror {} to {}: {}", url, full_path.display(), e),
+        }
+    }
+
+    /// Point links of the mirrored page to local copies of mirrored urls,
+    /// and make other links absolute.
+    fn convert_links(&self, url: &str, encoding: &str) -> io::Result<()> {
+        let (url, page_path): (Url, &PathBuf) = match (url.parse(), self.paths.get(url)) {
+            (Ok(url), Some(page_path)) => (url, page_path),
+            _ => return Ok(()),
+        };
+        let full_path = self.root.join(page_path);
+        let mut body = Vec::new();
+        try!(File::open(&full_path).and_then(|mut file| file.read_to_end(&mut body)));
+        let encoding = encoding_from_whatwg_label(encoding).unwrap_or(UTF_8 as EncodingRef);
+        let html = encoding.decode(&body, DecoderTrap::Replace)
+            .unwrap_or_else(|html| html.into_owned());
+        let html = rewrite_links(&html, |href| {
+            if href.trim().starts_with('#') {
+                return None;
+            }
+            let target = match url.join(href.trim()) {
+                Ok(ref target) if target.scheme() == "http" || target.scheme() == "https" =>
+                    target.clone(),
+                _ => return None,
+            };
+            let mut document = target.clone();
+            document.set_fragment(None);
+            Some(match self.paths.get(document.as_str()) {
+                Some(path) => {
+                    let mut link = relative_path(page_path, path).replace('%', "%25");
+                    if let Some(fragment) = target.fragment() {
+                        link.push('#');
+                        link.push_str(fragment);
+                    }
+                    link
+                },
+                None => target.as_str().to_owned(),
+            })
+        });
+        // Keep original encoding, so that meta charset stays correct
+        let body = encoding.encode(&html, EncoderTrap::NcrEscape)
+            .unwrap_or_else(|_| html.into_bytes());
+        File::create(&full_path).and_then(|mut file| file.write_all(&body))
+    }
+}
+
+impl Sink for MirrorWriter {
+    fn write_response(&mut self, request: &Request, response: &Option<Response>) {
+        if let Some(ref response) = *response {
+            if response.status == StatusCode::Ok {
+                if let Some(ref body) = response.body {
+                    self.write_file(&request.url, body);
+                }
+            }
+        }
+    }
+
+    fn write_result(&mut self, result: &CrawlResult) {
+        let is_html_page = !result.body_base64 &&
+            result.content_type.as_ref().map_or(true, |content_type| {
+                is_html(&content_type.split(';').next().unwrap_or("").trim().to_lowercase())
+            });
+        if self.rewrite_links && is_html_page && self.paths.contains_key(&result.url) {
+            self.pages.push((result.url.clone(), result.encoding.clone()));
+        }
+    }
+
+    fn close(&mut self) {
+        for (url, encoding) in mem::replace(&mut self.pages, Vec::new()) {
+            if let Err(e) = self.convert_links(&url, &encoding) {
+                error!("Can not rewrite links in mirrored {}: {}", url, e);
+            }
+        }
+    }
+}
+
+/// Relative path of the local copy of the url: "<host>/<path>".
+/// Directory urls are saved as "index.html", names without extension get ".html",
+/// query is added to the name after "@", unsafe characters are replaced with "_",
+/// and over-long names are shortened, with a hash of the full name added.
+pub fn local_path(url: &Url) -> PathBuf {
+    let host = url.host_str().unwrap_or("_");
+    let mut path = PathBuf::from(sanitize(&match url.port() {
+        Some(port) => format!("{}_{}", host, port),
+        None => host.to_owned(),
+    }));
+    let segments: Vec<&str> = url.path().trim_left_matches('/').split('/').collect();
+    // Split always returns at least one segment
+    let (name, dirs) = segments.split_last().unwrap();
+    for dir in dirs {
+        path.push(match *dir {
+            "" => "_".to_owned(),
+            "." | ".." => dir.replace('.', "_"),
+            _ => shorten(&sanitize(dir)),
+        });
+    }
+    let name = if name.is_empty() { "index.html" } else { name };
+    let (stem, extension) = match name.rfind('.') {
+        Some(idx) if idx > 0 => name.split_at(idx),
+        _ => (name, ".html"),
+    };
+    let name = match url.query() {
+        Some(query) => format!("{}@{}{}", stem, query, extension),
+        None => format!("{}{}", stem, extension),
+    };
+    path.push(shorten(&sanitize(&name)));
+    path
+}
+
+fn sanitize(name: &str) -> String {
+    name.chars().map(|c| match c {
+        '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+        c if c.is_control() => '_',
+        c => c,
+    }).collect()
+}
+
+fn shorten(name: &str) -> String {
+    if name.len() <= MAX_NAME_LENGTH {
+        return name.to_owned();
+    }
+    let extension = match name.rfind('.') {
+        Some(idx) if name.len() - idx <= 10 => &name[idx..],
+        _ => "",
+    };
+    let mut end = MAX_NAME_LENGTH - extension.len() - 17;
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}-{:016x}{}", &name[..end], hash(name), extension)
+}
+
+fn with_name_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+    let name = match name.rfind('.') {
+        Some(idx) if idx > 0 => format!("{}{}{}", &name[..idx], suffix, &name[idx..]),
+        _ => format!("{}{}", name, suffix),
+    };
+    path.with_file_name(name)
+}
+
+fn hash(value: &str) -> u64 {
+    let mut hasher = SipHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Path of the file "to" relative to the directory of the file "from".
+fn relative_path(from: &Path, to: &Path) -> String {
+    let from: Vec<Component> = from.parent().map_or(Vec::new(), |dir| dir.components().collect());
+    let to: Vec<Component> = to.components().collect();
+    let n_common = from.iter().zip(to.iter()).take_while(|&(a, b)| a == b).count();
+    let mut parts: Vec<String> = (n_common..from.len()).map(|_| "..".to_owned()).collect();
+    parts.extend(to[n_common..].iter()
+        .map(|component| component.as_os_str().to_string_lossy().into_owned()));
+    parts.join("/")
+}
+
+/// Serializes tokens back into HTML, replacing values of link attributes.
+struct LinkRewriter<F> {
+    html: String,
+    rewrite: F,
+    /// Text of script and style elements is written without escaping
+    in_raw_text: bool,
+    /// Raw text elements switch tokenizer state, like the tree builder does
+    state_change: Option<states::State>,
+}
+
+impl<F> LinkRewriter<F> where F: Fn(&str) -> Option<String> {
+    fn write_tag(&mut self, tag: Tag) {
+        if tag.kind == TagKind::EndTag {
+            self.html.push_str(&format!("</{}>", &*tag.name));
+            self.in_raw_text = false;
+            return;
+        }
+        self.html.push('<');
+        self.html.push_str(&*tag.name);
+        for attr in &tag.attrs {
+            let name = &*attr.name.local;
+            let is_link = LINK_ATTRIBUTES.iter().any(|&(tag_name, attr_name)| {
+                &*tag.name == tag_name && name == attr_name
+            });
+            let new_value = if is_link { (self.rewrite)(&attr.value) } else { None };
+            let value = new_value.as_ref().map_or(&*attr.value, |value| &value[..]);
+            self.html.push_str(&format!(" {}=\"{}\"", name, escape(value, true)));
+        }
+        if tag.self_closing {
+            self.html.push('/');
+        }
+        self.html.push('>');
+        // Character references are decoded in title and textarea, so their text is escaped
+        let raw_kind = match &*tag.name {
+            "script" => Some((states::RawKind::ScriptData, true)),
+            "style" | "xmp" | "iframe" | "noembed" | "noframes" =>
+                Some((states::RawKind::Rawtext, true)),
+            "title" | "textarea" => Some((states::RawKind::Rcdata, false)),
+            _ => None,
+        };
+        if let Some((raw_kind, in_raw_text)) = raw_kind {
+            self.in_raw_text = in_raw_text;
+            self.state_change = Some(states::State::RawData(raw_kind));
+        }
+    }
+}
+
+impl<F> TokenSink for LinkRewriter<F> where F: Fn(&str) -> Option<String> {
+    fn process_token(&mut self, token: Token) {
+        match token {
+            Token::DoctypeToken(doctype) => {
+                self.html.push_str("<!DOCTYPE");
+                if let Some(ref name) = doctype.name {
+                    self.html.push_str(&format!(" {}", name));
+                }
+                match (doctype.public_id, doctype.system_id) {
+                    (Some(public_id), Some(system_id)) => self.html.push_str(
+                        &format!(" PUBLIC \"{}\" \"{}\"", public_id, system_id)),
+                    (Some(public_id), None) =>
+                        self.html.push_str(&format!(" PUBLIC \"{}\"", public_id)),
+                    (None, Some(system_id)) =>
+                        self.html.push_str(&format!(" SYSTEM \"{}\"", system_id)),
+                    (None, None) => (),
+                }
+                self.html.push('>');
+            },
+            Token::TagToken(tag) => self.write_tag(tag),
+            Token::CommentToken(comment) => {
+                self.html.push_str(&format!("<!--{}-->", comment));
+            },
+            Token::CharacterTokens(text) => if self.in_raw_text {
+                self.html.push_str(&text);
+            } else {
+                self.html.push_str(&escape(&text, false));
+            },
+            Token::NullCharacterToken => self.html.push('\u{fffd}'),
+            _ => (),
+        }
+    }
+
+    fn query_state_change(&mut self) -> Option<states::State> {
+        self.state_change.take()
+    }
+}
+
+fn escape(text: &str, in_attribute: bool) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '\u{a0}' => escaped.push_str("&nbsp;"),
+            '"' if in_attribute => escaped.push_str("&quot;"),
+            '<' if !in_attribute => escaped.push_str("&lt;"),
+            '>' if !in_attribute => escaped.push_str("&gt;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Replace values of link attributes (see LINK_ATTRIBUTES) with values returned by rewrite
+/// (attributes are left as is if it returns None). The page is re-serialized from tokens,
+/// so markup is normalized: tag and attribute names are lowercased and values quoted.
+fn rewrite_links<F>(html: &str, rewrite: F) -> String where F: Fn(&str) -> Option<String> {
+    let mut tokenizer = Tokenizer::new(LinkRewriter {
+        html: String::with_capacity(html.len()),
+        rewrite: rewrite,
+        in_raw_text: false,
+        state_change: None,
+    }, TokenizerOpts::default());
+    tokenizer.feed(StrTendril::from(html));
+    tokenizer.end();
+    tokenizer.unwrap().html
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+    use std::path::Path;
+
+    use super::*;
+    use super::{relative_path, rewrite_links};
+
+    fn path(url: &str) -> String {
+        local_path(&url.parse().unwrap()).to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn test_local_path() {
+        assert_eq!(path("http://a.com"), "a.com/index.html");
+        assert_eq!(path("http://a.com/b/"), "a.com/b/index.html");
+        assert_eq!(path("http://a.com/b/c.pdf"), "a.com/b/c.pdf");
+        assert_eq!(path("http://a.com/b/c"), "a.com/b/c.html");
+        assert_eq!(path("http://a.com//c"), "a.com/_/c.html");
+        assert_eq!(path("http://a.com:8080/c.html?d=1&e=/f"), "a.com_8080/c@d=1&e=_f.html");
+        assert_eq!(path("https://a.com/?q"), "a.com/index@q.html");
+        assert_eq!(path("http://a.com/b%20c"), "a.com/b%20c.html");
+        let long_name = |n| iter::repeat("b").take(n).collect::<String>();
+        let long = path(&format!("http://a.com/{}.html", long_name(300)));
+        assert_eq!(long.len(), "a.com/".len() + MAX_NAME_LENGTH);
+        assert!(long.ends_with(".html"));
+        assert!(long != path(&format!("http://a.com/{}.html", long_name(301))));
+    }
+
+    #[test]
+    fn test_relative_path() {
+        let relative = |from: &str, to: &str| relative_path(Path::new(from), Path::new(to));
+        assert_eq!(relative("a.com/index.html", "a.com/b.html"), "b.html");
+        assert_eq!(relative("a.com/b/c.html", "a.com/d.html"), "../d.html");
+        assert_eq!(relative("a.com/b/c.html", "b.com/index.html"), "../../b.com/index.html");
+        assert_eq!(relative("a.com/c.html", "a.com/b/d.html"), "b/d.html");
+    }
+
+    #[test]
+    fn test_rewrite_links() {
+        let html = "<!DOCTYPE html><!-- c --><p><a href=\"b?c=1&amp;d=2\">b</a>\
+                    <A class=x HREF='/e'>e &amp; f</A><img src=g.png alt=\"a&quot;b\">\
+                    <script>if (a < b) {}</script><a>i</a><br/></p>";
+        let rewritten = rewrite_links(html, |href| Some(format!("[{}]", href)));
+        let normalized = "<!DOCTYPE html><!-- c --><p><a href=\"[b?c=1&amp;d=2]\">b</a>\
+                          <a class=\"x\" href=\"[/e]\">e &amp; f</a>\
+                          <img src=\"[g.png]\" alt=\"a&quot;b\">\
+                          <script>if (a < b) {}</script><a>i</a><br/></p>";
+        assert_eq!(rewritten, normalized);
+        let html = normalized.replace("[", "").replace("]", "");
+        assert_eq!(rewrite_links(&html, |_| None), html);
+    }
+
+    #[test]
+    fn test_mirror_writer() {
+        use std::env;
+        use std::fs::{self, File};
+        use std::io::Read;
+        use hyper::status::StatusCode;
+        use request::Request;
+        use response::test_response;
+        use result::CrawlResult;
+
+        let dir = env::temp_dir().join("crawler-test-mirror");
+        let _ = fs::remove_dir_all(&dir);
+        let mut writer = MirrorWriter::new(dir.to_str().unwrap(), true);
+        let response = |body: &[u8]| Some(test_response(StatusCode::Ok, Some(body)));
+        let body = b"<a href=\"/b/c?d#e\">\xe9</a><a href=\"index.html\">i</a>\
+                     <a href=\"/f\">f</a><a href=\"#g\">g</a><a href=\"mailto:h@a.com\">h</a>";
+        writer.write_response(&Request::from_str("http://a.com/b/"), &response(body));
+        writer.write_response(&Request::from_str("http://a.com/b/c?d"), &response(b"c"));
+        // Maps to the same file as "http://a.com/b/"
+        writer.write_response(&Request::from_str("http://a.com/b/index.html"), &response(b"i"));
+        let result = CrawlResult {
+            url: "http://a.com/b/".to_owned(),
+            original_url: "http://a.com/b/".to_owned(),
+            status: 200,
+            body_length: body.len(),
+            encoding: "windows-1252".to_owned(),
+            .. CrawlResult::default()
+        };
+        writer.write_result(&result);
+        writer.close();
+        let read = |path: &str| {
+            let mut contents = Vec::new();
+            File::open(dir.join(path)).unwrap().read_to_end(&mut contents).unwrap();
+            contents
+        };
+        let mut paths: Vec<_> = fs::read_dir(dir.join("a.com/b")).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        paths.sort();
+        assert_eq!(paths.len(), 3);
+        let renamed = &paths[1];
+        assert!(renamed.starts_with("index-") && renamed.ends_with(".html"));
+        assert_eq!(read(&format!("a.com/b/{}", renamed)), b"i");
+        // Mirrored targets are relative, others absolute, encoding is kept
+        let mut expected = b"<a href=\"c@d.html#e\">\xe9</a><a href=\"".to_vec();
+        expected.extend(renamed.as_bytes());
+        expected.extend(b"\">i</a><a href=\"http://a.com/f\">f</a><a href=\"#g\">g</a>\
+                          <a href=\"mailto:h@a.com\">h</a>".iter());
+        assert_eq!(read("a.com/b/index.html"), expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
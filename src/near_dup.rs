@@ -0,0 +1,122 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher, SipHasher};
+
+
+/// Texts with fewer words are not hashed: short pages (like error pages or stubs)
+/// differ in too few shingles, so unrelated ones would be taken for near-duplicates.
+pub const MIN_TOKENS: usize = 20;
+
+/// SimHash of the text, computed over shingles of three consecutive lowercased words.
+/// Similar texts have hashes with small Hamming distance.
+/// None if the text has fewer than MIN_TOKENS words.
+pub fn simhash(text: &str) -> Option<u64> {
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect();
+    if words.len() < MIN_TOKENS {
+        return None;
+    }
+    let shingle_size = cmp::min(3, words.len());
+    let mut weights = [0i64; 64];
+    for shingle in words.windows(shingle_size) {
+        let mut hasher = SipHasher::new();
+        shingle.hash(&mut hasher);
+        let hash = hasher.finish();
+        for (bit, weight) in weights.iter_mut().enumerate() {
+            if hash & (1 << bit) != 0 {
+                *weight += 1;
+            } else {
+                *weight -= 1;
+            }
+        }
+    }
+    Some(weights.iter().enumerate()
+        .filter(|&(_, &weight)| weight > 0)
+        .fold(0, |hash, (bit, _)| hash | (1 << bit)))
+}
+
+/// Index of SimHashes that finds hashes within max_distance bits of a given one.
+/// Hashes are split into max_distance + 1 blocks: a hash within max_distance
+/// must match at least one block exactly, so only hashes sharing a block are compared.
+pub struct NearDupIndex {
+    max_distance: u32,
+    blocks: Vec<(u32, u32)>,
+    tables: Vec<HashMap<u64, Vec<u64>>>,
+}
+
+impl NearDupIndex {
+    pub fn new(max_distance: u32) -> Self {
+        let n_blocks = cmp::min(max_distance + 1, 64);
+        let blocks = (0..n_blocks)
+            .map(|idx| (idx * 64 / n_blocks, (idx + 1) * 64 / n_blocks))
+            .collect();
+        NearDupIndex {
+            max_distance: max_distance,
+            blocks: blocks,
+            tables: (0..n_blocks).map(|_| HashMap::new()).collect(),
+        }
+    }
+
+    /// Check if a near-duplicate of the hash was already added, add it if not.
+    pub fn check_and_add(&mut self, hash: u64) -> bool {
+        let max_distance = self.max_distance;
+        for (&(start, end), table) in self.blocks.iter().zip(self.tables.iter()) {
+            if let Some(candidates) = table.get(&block_key(hash, start, end)) {
+                if candidates.iter().any(|&other| (hash ^ other).count_ones() <= max_distance) {
+                    return true;
+                }
+            }
+        }
+        for (&(start, end), table) in self.blocks.iter().zip(self.tables.iter_mut()) {
+            table.entry(block_key(hash, start, end)).or_insert_with(Vec::new).push(hash);
+        }
+        false
+    }
+}
+
+fn block_key(hash: u64, start: u32, end: u32) -> u64 {
+    let width = end - start;
+    let mask = if width == 64 { !0 } else { (1 << width) - 1 };
+    (hash >> start) & mask
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_simhash() {
+        let text = "The quick brown fox jumps over the lazy dog, \
+                    and then the dog wakes up and chases the fox through the field \
+                    until both of them are tired and fall asleep under an old oak tree";
+        let similar = text.replace("old oak", "old pine").to_uppercase();
+        let different = "Lorem ipsum dolor sit amet, consectetur adipiscing elit, \
+                         sed do eiusmod tempor incididunt ut labore et dolore magna aliqua. \
+                         Ut enim ad minim veniam";
+        let hash = simhash(text).unwrap();
+        assert_eq!(simhash(&text.to_uppercase()), Some(hash));
+        assert!((hash ^ simhash(&similar).unwrap()).count_ones() <= 12);
+        assert!((hash ^ simhash(different).unwrap()).count_ones() > 12);
+        assert_eq!(simhash(""), None);
+        assert_eq!(simhash("404 Not Found: the requested page does not exist"), None);
+    }
+
+    #[test]
+    fn test_index() {
+        let mut index = NearDupIndex::new(3);
+        assert!(!index.check_and_add(0b1111_0000));
+        assert!(index.check_and_add(0b1111_0000));
+        assert!(index.check_and_add(0b1111_0111));
+        assert!(!index.check_and_add(0b1111_1111 << 40 | 0b1111));
+        assert!(index.check_and_add(0b1111_1110 << 40 | 0b1111));
+        assert!(!index.check_and_add(!0));
+        let mut exact = NearDupIndex::new(0);
+        assert!(!exact.check_and_add(1));
+        assert!(!exact.check_and_add(3));
+        assert!(exact.check_and_add(3));
+    }
+}
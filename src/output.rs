@@ -0,0 +1,332 @@
+use std::fs::OpenOptions;
+use std::fs::File;
+use std::io::{self, Write};
+use std::time::{Duration, Instant, SystemTime};
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use zstd;
+
+use warc::format_timestamp;
+
+
+/// Compression of output files.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputCompression {
+    None,
+    /// ".gz" is appended to file names
+    Gzip,
+    /// ".zst" is appended to file names
+    Zstd,
+}
+
+/// When to start a new output file. If no limits are set, all records are appended
+/// to a single file, else each file is named by its start time and sequence number,
+/// e.g. "out-20160701120000-00000.jl".
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputRotation {
+    /// Uncompressed size in bytes
+    pub max_size: Option<u64>,
+    pub max_records: Option<u64>,
+    pub max_age: Option<Duration>,
+}
+
+impl OutputRotation {
+    fn is_enabled(&self) -> bool {
+        self.max_size.is_some() || self.max_records.is_some() || self.max_age.is_some()
+    }
+}
+
+impl Default for OutputRotation {
+    fn default() -> Self {
+        OutputRotation {
+            max_size: None,
+            max_records: None,
+            max_age: None,
+        }
+    }
+}
+
+enum FileWriter {
+    Plain(File),
+    Gzip(GzEncoder<File>),
+    Zstd(zstd::Encoder<File>),
+}
+
+impl FileWriter {
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        match *self {
+            FileWriter::Plain(ref mut file) => file.write_all(data),
+            FileWriter::Gzip(ref mut encoder) => encoder.write_all(data),
+            FileWriter::Zstd(ref mut encoder) => encoder.write_all(data),
+        }
+    }
+
+    /// Finish the gzip member or zstd frame, returning the file.
+    fn finish(self) -> io::Result<File> {
+        match self {
+            FileWriter::Plain(mut file) => file.flush().map(|_| file),
+            FileWriter::Gzip(encoder) => encoder.finish(),
+            FileWriter::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+/// Writes records into possibly compressed files, starting new files
+/// according to rotation settings. Plain files are flushed after each record,
+/// compressed files are readable up to the last flush, rotation or close.
+pub struct RotatingWriter {
+    path: String,
+    rotation: OutputRotation,
+    compression: OutputCompression,
+    writer: Option<FileWriter>,
+    size: u64,
+    n_records: u64,
+    /// Current file after compressed data was flushed, until the next record
+    flushed: Option<File>,
+    opened_at: Instant,
+    n_files: u32,
+}
+
+impl RotatingWriter {
+    pub fn new(path: &str, rotation: &OutputRotation, compression: OutputCompression) -> Self {
+        RotatingWriter {
+            path: path.to_owned(),
+            rotation: rotation.clone(),
+            compression: compression,
+            writer: None,
+            size: 0,
+            n_records: 0,
+            flushed: None,
+            opened_at: Instant::now(),
+            n_files: 0,
+        }
+    }
+
+    /// Write a complete record, it is never split between files.
+    pub fn write_record(&mut self, record: &[u8]) {
+        if let Some(max_age) = self.rotation.max_age {
+            let is_open = self.writer.is_some() || self.flushed.is_some();
+            if is_open && self.opened_at.elapsed() >= max_age {
+                self.close();
+            }
+        }
+        if self.writer.is_none() {
+            match self.flushed.take() {
+                Some(file) => self.writer = Some(self.file_writer(file)),
+                None => self.open_file(),
+            }
+        }
+        if let Some(ref mut writer) = self.writer {
+            writer.write_all(record).unwrap();
+            if let FileWriter::Plain(ref mut file) = *writer {
+                file.flush().unwrap();
+            }
+        }
+        self.size += record.len() as u64;
+        self.n_records += 1;
+        let is_full = self.rotation.max_size.map_or(false, |max_size| self.size >= max_size) ||
+            self.rotation.max_records.map_or(false, |max_records| self.n_records >= max_records);
+        if is_full {
+            self.close();
+        }
+    }
+
+    /// Make records written so far readable even if the crawl is killed:
+    /// current gzip member or zstd frame is finished, and the next record starts
+    /// a new one in the same file.
+    pub fn flush(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            self.flushed = Some(writer.finish().unwrap());
+        }
+    }
+
+    /// Finish current file, next record will start a new one.
+    pub fn close(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            writer.finish().unwrap();
+        }
+        self.flushed = None;
+    }
+
+    fn open_file(&mut self) {
+        let path = self.next_path();
+        info!("Writing output to {}", path);
+        let file = OpenOptions::new().create(true).append(true).open(&path).unwrap();
+        self.writer = Some(self.file_writer(file));
+        self.size = 0;
+        self.n_records = 0;
+        self.opened_at = Instant::now();
+        self.n_files += 1;
+    }
+
+    fn file_writer(&self, file: File) -> FileWriter {
+        match self.compression {
+            OutputCompression::None => FileWriter::Plain(file),
+            OutputCompression::Gzip =>
+                FileWriter::Gzip(GzEncoder::new(file, Compression::Default)),
+            OutputCompression::Zstd =>
+                FileWriter::Zstd(zstd::Encoder::new(file, 3).unwrap()),
+        }
+    }
+
+    fn next_path(&self) -> String {
+        let mut path = if self.rotation.is_enabled() {
+            let timestamp: String = format_timestamp(SystemTime::now()).chars()
+                .filter(|c| c.is_digit(10)).collect();
+            let name_start = self.path.rfind('/').map_or(0, |idx| idx + 1);
+            let (stem, extension) = match self.path[name_start..].rfind('.') {
+                Some(idx) if idx > 0 => self.path.split_at(name_start + idx),
+                _ => (self.path.as_str(), ""),
+            };
+            format!("{}-{}-{:05}{}", stem, timestamp, self.n_files, extension)
+        } else {
+            self.path.clone()
+        };
+        match self.compression {
+            OutputCompression::None => (),
+            OutputCompression::Gzip => path.push_str(".gz"),
+            OutputCompression::Zstd => path.push_str(".zst"),
+        }
+        path
+    }
+}
+
+impl Drop for RotatingWriter {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Read;
+
+    use flate2::read::{GzDecoder, MultiGzDecoder};
+    use zstd;
+    use super::*;
+
+    fn test_dir(name: &str) -> String {
+        let dir = env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_str().unwrap().to_owned()
+    }
+
+    fn read_dir(dir: &str) -> Vec<String> {
+        let mut paths: Vec<String> = fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().path().to_str().unwrap().to_owned())
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn test_no_rotation() {
+        let dir = test_dir("crawler-test-output-no-rotation");
+        let path = format!("{}/out.jl", dir);
+        for _ in 0..2 {
+            let mut writer = RotatingWriter::new(
+                &path, &OutputRotation::default(), OutputCompression::None);
+            writer.write_record(b"a\n");
+        }
+        let mut contents = String::new();
+        File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "a\na\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotation_gzip() {
+        let dir = test_dir("crawler-test-output-rotation-gzip");
+        let rotation = OutputRotation { max_records: Some(2), .. OutputRotation::default() };
+        {
+            let mut writer = RotatingWriter::new(
+                &format!("{}/out.jl", dir), &rotation, OutputCompression::Gzip);
+            for record in &["a\n", "b\n", "c\n"] {
+                writer.write_record(record.as_bytes());
+            }
+        }
+        let paths = read_dir(&dir);
+        assert_eq!(paths.len(), 2);
+        assert!(paths[0].ends_with("-00000.jl.gz"));
+        assert!(paths[1].ends_with("-00001.jl.gz"));
+        let contents: Vec<String> = paths.iter().map(|path| {
+            let mut contents = String::new();
+            GzDecoder::new(File::open(path).unwrap()).unwrap()
+                .read_to_string(&mut contents).unwrap();
+            contents
+        }).collect();
+        assert_eq!(contents, vec!["a\nb\n", "c\n"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_flush() {
+        let dir = test_dir("crawler-test-output-flush");
+        for &compression in &[OutputCompression::Gzip, OutputCompression::Zstd] {
+            let mut writer = RotatingWriter::new(
+                &format!("{}/out.jl", dir), &OutputRotation::default(), compression);
+            writer.write_record(b"a\n");
+            writer.flush();
+            writer.flush();
+            writer.write_record(b"b\n");
+            writer.flush();
+            // Writer is not closed yet, as if the crawl was killed
+            let path = read_dir(&dir).pop().unwrap();
+            let mut contents = String::new();
+            match compression {
+                OutputCompression::Gzip =>
+                    MultiGzDecoder::new(File::open(&path).unwrap()).unwrap()
+                        .read_to_string(&mut contents).unwrap(),
+                _ =>
+                    zstd::Decoder::new(File::open(&path).unwrap()).unwrap()
+                        .read_to_string(&mut contents).unwrap(),
+            };
+            assert_eq!(contents, "a\nb\n");
+            writer.close();
+            fs::remove_file(&path).unwrap();
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_zstd() {
+        let dir = test_dir("crawler-test-output-zstd");
+        let path = format!("{}/out.jl", dir);
+        for record in &["a\n", "b\n"] {
+            let mut writer = RotatingWriter::new(
+                &path, &OutputRotation::default(), OutputCompression::Zstd);
+            writer.write_record(record.as_bytes());
+        }
+        let paths = read_dir(&dir);
+        assert_eq!(paths.len(), 1);
+        assert!(paths[0].ends_with("out.jl.zst"));
+        let mut contents = String::new();
+        zstd::Decoder::new(File::open(&paths[0]).unwrap()).unwrap()
+            .read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "a\nb\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotation_by_size() {
+        let dir = test_dir("crawler-test-output-rotation-size");
+        let rotation = OutputRotation { max_size: Some(3), .. OutputRotation::default() };
+        {
+            let mut writer = RotatingWriter::new(
+                &format!("{}/log", dir), &rotation, OutputCompression::None);
+            for record in &["ab\n", "c\n", "d\n"] {
+                writer.write_record(record.as_bytes());
+            }
+        }
+        let paths = read_dir(&dir);
+        assert_eq!(paths.len(), 2);
+        assert!(paths[0].ends_with("-00000"));
+        assert!(paths[1].ends_with("-00001"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,58 +1,191 @@
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::HashMap;
+use std::io;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
+use rand;
 use url::Host;
 
+use canonicalize::CanonicalizeOptions;
+use dedupe::{SeenSet, new_seen_set};
+use frontier::{DomainFrontier, FrontierStorage, new_domain_frontier};
 use request::Request;
+use robots::RobotsTxt;
 use settings::Settings;
 
 
+enum RobotsState {
+    Unknown,
+    Fetching,
+    Known(Rc<RobotsTxt>),
+}
+
 struct DomainQueue {
-    deque: VecDeque<Request>,
+    deque: Box<DomainFrontier>,
     n_pending: u32,
+    robots: RobotsState,
+    // The domain is skipped by RequestQueue::pop until this time
+    next_fetch: Instant,
 }
 
 
 pub struct RequestQueue {
-    seen_requests: HashSet<u64>,
+    seen_requests: Box<SeenSet>,
     deques: HashMap<Option<Host>, DomainQueue>,
+    // Requests returned by self.pop, waiting for self.decr_pending
+    in_flight: HashMap<u64, Request>,
+    // Robots.txt redirect targets, returned by self.pop before other requests
+    robots_redirects: Vec<Request>,
+    frontier_storage: FrontierStorage,
+    canonicalize_options: CanonicalizeOptions,
+    robots_cache: Option<HashMap<Option<Host>, Rc<RobotsTxt>>>,
+    allow_all: Rc<RobotsTxt>,
     n_pending: u32,
+    n_robots_disallowed: u64,
     max_pending: u32,
     max_per_domain: u32,
+    download_delay: Duration,
+    randomize_download_delay: bool,
+    max_depth: Option<u32>,
 }
 
 impl RequestQueue {
     pub fn new(settings: &Settings) -> Self {
         RequestQueue {
-            seen_requests: HashSet::new(),
+            seen_requests: new_seen_set(&settings.dedupe_storage),
             deques: HashMap::new(),
+            in_flight: HashMap::new(),
+            robots_redirects: Vec::new(),
+            frontier_storage: settings.frontier_storage.clone(),
+            canonicalize_options: settings.canonicalize.clone(),
+            robots_cache: if settings.obey_robots_txt { Some(HashMap::new()) } else { None },
+            allow_all: Rc::new(RobotsTxt::allow_all()),
             max_pending: settings.concurrent_requests,
             max_per_domain: settings.concurrent_requests_per_domain,
+            download_delay: settings.download_delay,
+            randomize_download_delay: settings.randomize_download_delay,
+            max_depth: settings.max_depth,
             n_pending: 0,
+            n_robots_disallowed: 0,
         }
     }
 
     pub fn push(&mut self, request: Request) {
-        let fingerprint = request.get_fingerprint();
+        if let Some(max_depth) = self.max_depth {
+            if request.depth > max_depth {
+                return;
+            }
+        }
+        let fingerprint = request.get_fingerprint_with(&self.canonicalize_options);
         if self.seen_requests.insert(fingerprint) {
-            let key = self.get_key(&request);
-            let domain_queue = self.deques.entry(key).or_insert_with(|| {
-                DomainQueue { deque: VecDeque::new(), n_pending: 0 }
-            });
-            domain_queue.deque.push_back(request);
+            self.enqueue(request);
+        }
+    }
+
+    /// Create a queue from a checkpoint: seen fingerprints and requests that were
+    /// queued or in flight.
+    pub fn restore(settings: &Settings, seen_requests: Box<SeenSet>, requests: Vec<Request>)
+            -> Self {
+        let mut queue = RequestQueue::new(settings);
+        queue.seen_requests = seen_requests;
+        for request in requests {
+            queue.enqueue(request);
+        }
+        queue
+    }
+
+    /// All queued and in flight requests (except robots.txt requests).
+    pub fn get_requests(&self) -> io::Result<Vec<Request>> {
+        let mut requests: Vec<_> = self.in_flight.values().cloned().collect();
+        for domain_queue in self.deques.values() {
+            requests.extend(try!(domain_queue.deque.requests()));
+        }
+        Ok(requests)
+    }
+
+    pub fn get_seen_requests(&self) -> &SeenSet {
+        &*self.seen_requests
+    }
+
+    fn enqueue(&mut self, request: Request) {
+        let key = self.get_key(&request);
+        let robots = match self.robots_cache {
+            Some(ref robots_cache) => match robots_cache.get(&key) {
+                Some(robots) => RobotsState::Known(robots.clone()),
+                None => RobotsState::Unknown,
+            },
+            None => RobotsState::Known(self.allow_all.clone()),
+        };
+        let domain = key.as_ref().map(|host| host.to_string())
+            .unwrap_or_else(String::new);
+        let frontier_storage = &self.frontier_storage;
+        let domain_queue = self.deques.entry(key).or_insert_with(|| {
+            DomainQueue {
+                deque: new_domain_frontier(frontier_storage, &domain),
+                n_pending: 0,
+                robots: robots,
+                next_fetch: Instant::now(),
+            }
+        });
+        if let RobotsState::Known(ref robots) = domain_queue.robots {
+            if !robots.is_allowed(&request.url) {
+                debug!("Forbidden by robots.txt: {}", request.url);
+                self.n_robots_disallowed += 1;
+                return;
+            }
         }
+        domain_queue.deque.push_back(request);
     }
 
     pub fn pop(&mut self) -> Option<Request> {
-        // Find the first domain queue that is not empty and has free slots, and pop from it.
+        // Find the first domain queue that is not empty, has free slots and is not delayed,
+        // and pop from it. If robots.txt for the domain is not known yet, fetch it first.
         if self.n_pending < self.max_pending {
+            if let Some(request) = self.robots_redirects.pop() {
+                let key = self.get_key(&request);
+                if let Some(domain_queue) = self.deques.get_mut(&key) {
+                    domain_queue.n_pending += 1;
+                }
+                self.n_pending += 1;
+                return Some(request);
+            }
+            let now = Instant::now();
             // FIXME - order is not random here, but this is not a huge problem, because empty
             // queues are removed.
             for domain_queue in self.deques.values_mut() {
-                if domain_queue.n_pending < self.max_per_domain {
-                    let request = domain_queue.deque.pop_front();
+                if domain_queue.n_pending < self.max_per_domain && domain_queue.next_fetch <= now {
+                    let request = match domain_queue.robots {
+                        RobotsState::Known(_) => match domain_queue.deque.pop_front() {
+                            Ok(request) => request,
+                            Err(e) => {
+                                error!("Error reading domain queue: {}", e);
+                                None
+                            }
+                        },
+                        RobotsState::Fetching => None,
+                        RobotsState::Unknown => {
+                            let robots_request = domain_queue.deque.front()
+                                .and_then(|request| Request::robots_txt(&request.url));
+                            domain_queue.robots = match robots_request {
+                                Some(_) => RobotsState::Fetching,
+                                None => RobotsState::Known(self.allow_all.clone()),
+                            };
+                            robots_request
+                        }
+                    };
+                    if let Some(ref request) = request {
+                        if !request.is_robots_txt {
+                            self.in_flight.insert(
+                                request.get_fingerprint_with(&self.canonicalize_options),
+                                request.clone());
+                        }
+                    }
                     if request.is_some() {
                         self.n_pending += 1;
                         domain_queue.n_pending += 1;
+                        domain_queue.next_fetch = now + get_delay(
+                            self.download_delay, self.randomize_download_delay,
+                            &domain_queue.robots);
                         return request;
                     }
                 }
@@ -61,8 +194,60 @@ impl RequestQueue {
         None
     }
 
+    /// Fetch robots.txt from the redirect target of a robots.txt request.
+    /// Rules of the target apply to the domain of the original request.
+    /// Must be called before decr_pending for the redirected request.
+    pub fn push_robots_redirect(&mut self, request: Request) {
+        self.robots_redirects.push(request);
+    }
+
+    /// Remember robots.txt rules for the domain of the robots.txt request,
+    /// dropping already queued requests that are not allowed.
+    /// Must be called before decr_pending for this request.
+    pub fn set_robots(&mut self, request: &Request, robots: RobotsTxt) {
+        let key = self.get_key(request);
+        let robots = Rc::new(robots);
+        if let Some(ref mut robots_cache) = self.robots_cache {
+            robots_cache.insert(key.clone(), robots.clone());
+        }
+        if let Some(domain_queue) = self.deques.get_mut(&key) {
+            let n_before = domain_queue.deque.len();
+            match domain_queue.deque.retain(&|request: &Request| robots.is_allowed(&request.url)) {
+                Ok(()) => self.n_robots_disallowed += (n_before - domain_queue.deque.len()) as u64,
+                Err(e) => error!("Error reading domain queue: {}", e),
+            }
+            domain_queue.robots = RobotsState::Known(robots);
+        }
+    }
+
+    /// Return time until the next delayed domain can be popped from,
+    /// and remove empty domain queues whose delay has passed.
+    pub fn time_to_next_ready(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        let mut next_ready = None;
+        let mut idle_keys = Vec::new();
+        for (key, domain_queue) in &self.deques {
+            if domain_queue.next_fetch <= now {
+                if domain_queue.n_pending == 0 && domain_queue.deque.is_empty() {
+                    idle_keys.push(key.clone());
+                }
+            } else if !domain_queue.deque.is_empty() {
+                next_ready = match next_ready {
+                    Some(next) if next < domain_queue.next_fetch => Some(next),
+                    _ => Some(domain_queue.next_fetch),
+                };
+            }
+        }
+        for key in idle_keys {
+            self.deques.remove(&key);
+        }
+        next_ready.map(|next_ready| next_ready - now)
+    }
+
     pub fn is_empty(&self) -> bool {
-        self.n_pending == 0 && self.deques.is_empty()
+        // Empty domain queues can be kept until their delay passes
+        self.n_pending == 0 && self.robots_redirects.is_empty() &&
+            self.deques.values().all(|q| q.deque.is_empty())
     }
 
     pub fn decr_pending(&mut self, request: &Request) {
@@ -70,6 +255,9 @@ impl RequestQueue {
             panic!("decr_pending expected self.n_pending to be positive");
         }
         self.n_pending -= 1;
+        if !request.is_robots_txt {
+            self.in_flight.remove(&request.get_fingerprint_with(&self.canonicalize_options));
+        }
         let key = self.get_key(request);
         let mut domain_queue_empty = false;
         if let Some(domain_queue) = self.deques.get_mut(&key) {
@@ -77,9 +265,11 @@ impl RequestQueue {
                 panic!("decr_pending expected domain_queue.n_pending to be positive");
             }
             domain_queue.n_pending -= 1;
-            if domain_queue.n_pending == 0 && domain_queue.deque.is_empty() {
+            if domain_queue.n_pending == 0 && domain_queue.deque.is_empty() &&
+                    domain_queue.next_fetch <= Instant::now() {
                 // The queue can become empty in self.pop too, but then it will have n_pending > 0,
-                // so it is enough to check that here.
+                // so it is enough to check that here. Queues that are still delayed are kept
+                // to remember the delay, they are removed in self.time_to_next_ready.
                 domain_queue_empty = true;
             }
         } else {
@@ -98,8 +288,14 @@ impl RequestQueue {
         return self.n_pending;
     }
 
+    pub fn get_n_robots_disallowed(&self) -> u64 {
+        return self.n_robots_disallowed;
+    }
+
     fn get_key(&self, request: &Request) -> Option<Host> {
-        if let Some(host) = request.url.host() {
+        // Redirected robots.txt requests belong to the domain they were made for
+        let url = if request.is_robots_txt { request.original_url() } else { &request.url };
+        if let Some(host) = url.host() {
             // TODO - extract top-level domain
             Some(host.to_owned())
         } else {
@@ -108,84 +304,304 @@ impl RequestQueue {
     }
 }
 
+/// Delay before the next request to the domain: download_delay (randomized if needed),
+/// but never less than robots.txt Crawl-delay.
+fn get_delay(download_delay: Duration, randomize: bool, robots: &RobotsState) -> Duration {
+    let mut delay = download_delay;
+    if randomize && delay > Duration::from_secs(0) {
+        // Random delay between 0.5 and 1.5 of the original value
+        let delay_ms = delay.as_secs() as f64 * 1e3 + delay.subsec_nanos() as f64 * 1e-6;
+        delay = Duration::from_millis((delay_ms * (0.5 + rand::random::<f64>())) as u64);
+    }
+    if let RobotsState::Known(ref robots) = *robots {
+        if let Some(crawl_delay) = robots.crawl_delay() {
+            if crawl_delay > delay {
+                delay = crawl_delay;
+            }
+        }
+    }
+    delay
+}
+
 #[cfg(test)]
 mod tests {
+    use std::env;
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+    use std::thread;
+    use std::time::Duration;
+
+    use frontier::FrontierStorage;
     use request::Request;
+    use robots::RobotsTxt;
     use settings::Settings;
     use super::*;
 
-    fn request_queue(concurrent_requests_per_domain: u32) -> RequestQueue {
+    static N_QUEUES: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    /// Queues with in-memory and on-disk frontier storage
+    fn request_queues(concurrent_requests_per_domain: u32) -> Vec<RequestQueue> {
         let mut settings = Settings::default();
         settings.concurrent_requests_per_domain = concurrent_requests_per_domain;
-        RequestQueue::new(&settings)
+        settings.obey_robots_txt = false;
+        let memory_queue = RequestQueue::new(&settings);
+        let path = env::temp_dir().join(
+            format!("crawler-test-queue-{}", N_QUEUES.fetch_add(1, Ordering::SeqCst)));
+        settings.frontier_storage = FrontierStorage::Disk {
+            path: path.to_str().unwrap().to_owned(),
+            head_size: 1,
+        };
+        vec![memory_queue, RequestQueue::new(&settings)]
     }
 
     #[test]
     fn test_push_pop() {
-        let mut queue = request_queue(2);
-        assert_eq!(queue.is_empty(), true);
-        queue.push(Request::from_str("http://domain-1.com/a"));
-        assert_eq!(queue.is_empty(), false);
-        assert_eq!(queue.pop().unwrap().url.as_str(), "http://domain-1.com/a");
-        assert_eq!(queue.is_empty(), false);
-        queue.decr_pending(&Request::from_str("http://domain-1.com/a"));
-        assert_eq!(queue.is_empty(), true);
-        assert_eq!(queue.pop(), None);
-        assert_eq!(queue.is_empty(), true);
+        for mut queue in request_queues(2) {
+            assert_eq!(queue.is_empty(), true);
+            queue.push(Request::from_str("http://domain-1.com/a"));
+            assert_eq!(queue.is_empty(), false);
+            assert_eq!(queue.pop().unwrap().url.as_str(), "http://domain-1.com/a");
+            assert_eq!(queue.is_empty(), false);
+            queue.decr_pending(&Request::from_str("http://domain-1.com/a"));
+            assert_eq!(queue.is_empty(), true);
+            assert_eq!(queue.pop(), None);
+            assert_eq!(queue.is_empty(), true);
+        }
     }
 
     #[test]
     fn test_pop_empty() {
-        let mut queue = request_queue(2);
+        for mut queue in request_queues(2) {
+            queue.push(Request::from_str("http://domain-1.com/a"));
+            assert_eq!(queue.is_empty(), false);
+            assert_eq!(queue.pop().unwrap().url.as_str(), "http://domain-1.com/a");
+            assert_eq!(queue.is_empty(), false);
+            assert_eq!(queue.pop(), None);
+            assert_eq!(queue.is_empty(), false);
+            queue.decr_pending(&Request::from_str("http://domain-1.com/a"));
+            assert_eq!(queue.is_empty(), true);
+        }
+    }
+
+    #[test]
+    fn test_domain_limit() {
+        for mut queue in request_queues(2) {
+            queue.push(Request::from_str("http://domain-1.com/a"));
+            queue.push(Request::from_str("http://domain-1.com/b"));
+            queue.push(Request::from_str("http://domain-1.com/c"));
+            assert_eq!(queue.pop().unwrap().url.as_str(), "http://domain-1.com/a");
+            assert_eq!(queue.pop().unwrap().url.as_str(), "http://domain-1.com/b");
+            assert_eq!(queue.pop(), None);
+            queue.push(Request::from_str("http://domain-2.com/a"));
+            assert_eq!(queue.pop().unwrap().url.as_str(), "http://domain-2.com/a");
+            assert_eq!(queue.pop(), None);
+            queue.decr_pending(&Request::from_str("http://domain-1.com/a"));
+            assert_eq!(queue.pop().unwrap().url.as_str(), "http://domain-1.com/c");
+            assert_eq!(queue.pop(), None);
+        }
+    }
+
+    #[test]
+    fn test_sampling() {
+        // Run with $ cargo test test_sampling -- --nocapture
+        for mut queue in request_queues(3) {
+            queue.push(Request::from_str("http://domain-1.com/a"));
+            queue.push(Request::from_str("http://domain-1.com/b"));
+            queue.push(Request::from_str("http://domain-1.com/c"));
+            queue.push(Request::from_str("http://domain-2.com/a"));
+            queue.push(Request::from_str("http://domain-2.com/b"));
+            queue.push(Request::from_str("http://domain-2.com/c"));
+            while let Some(request) = queue.pop() {
+                println!("{:?}", request);
+            }
+        }
+    }
+
+    #[test]
+    fn test_duplicates() {
+        // Run with $ cargo test test_sampling -- --nocapture
+        for mut queue in request_queues(3) {
+            queue.push(Request::from_str("http://domain-1.com/a"));
+            queue.push(Request::from_str("http://domain-1.com/a"));
+            assert!(queue.pop().is_some());
+            assert_eq!(queue.pop(), None);
+        }
+    }
+
+    #[test]
+    fn test_robots() {
+        let mut settings = Settings::default();
+        settings.obey_robots_txt = true;
+        let mut queue = RequestQueue::new(&settings);
         queue.push(Request::from_str("http://domain-1.com/a"));
-        assert_eq!(queue.is_empty(), false);
+        queue.push(Request::from_str("http://domain-1.com/private/b"));
+        let robots_request = queue.pop().unwrap();
+        assert!(robots_request.is_robots_txt);
+        assert_eq!(robots_request.url.as_str(), "http://domain-1.com/robots.txt");
+        // Nothing else is fetched from the domain until robots.txt arrives
+        assert_eq!(queue.pop(), None);
+        queue.set_robots(&robots_request,
+                         RobotsTxt::parse("User-agent: *\nDisallow: /private", "crawler"));
+        queue.decr_pending(&robots_request);
         assert_eq!(queue.pop().unwrap().url.as_str(), "http://domain-1.com/a");
-        assert_eq!(queue.is_empty(), false);
         assert_eq!(queue.pop(), None);
-        assert_eq!(queue.is_empty(), false);
+        assert_eq!(queue.get_n_robots_disallowed(), 1);
+        queue.push(Request::from_str("http://domain-1.com/private/c"));
+        assert_eq!(queue.get_n_robots_disallowed(), 2);
         queue.decr_pending(&Request::from_str("http://domain-1.com/a"));
-        assert_eq!(queue.is_empty(), true);
+        assert!(queue.is_empty());
+        // Rules are cached after domain queue is removed
+        queue.push(Request::from_str("http://domain-1.com/d"));
+        assert_eq!(queue.pop().unwrap().url.as_str(), "http://domain-1.com/d");
     }
 
     #[test]
-    fn test_domain_limit() {
-        let mut queue = request_queue(2);
+    fn test_robots_redirect() {
+        let mut settings = Settings::default();
+        settings.obey_robots_txt = true;
+        let mut queue = RequestQueue::new(&settings);
         queue.push(Request::from_str("http://domain-1.com/a"));
         queue.push(Request::from_str("http://domain-1.com/b"));
-        queue.push(Request::from_str("http://domain-1.com/c"));
-        assert_eq!(queue.pop().unwrap().url.as_str(), "http://domain-1.com/a");
-        assert_eq!(queue.pop().unwrap().url.as_str(), "http://domain-1.com/b");
-        assert_eq!(queue.pop(), None);
-        queue.push(Request::from_str("http://domain-2.com/a"));
-        assert_eq!(queue.pop().unwrap().url.as_str(), "http://domain-2.com/a");
+        let robots_request = queue.pop().unwrap();
+        let redirect = robots_request.redirect(
+            "http://www.domain-1.com/robots.txt".parse().unwrap(), 301);
+        queue.push_robots_redirect(redirect);
+        queue.decr_pending(&robots_request);
+        let redirect = queue.pop().unwrap();
+        assert!(redirect.is_robots_txt);
+        assert_eq!(redirect.url.as_str(), "http://www.domain-1.com/robots.txt");
         assert_eq!(queue.pop(), None);
-        queue.decr_pending(&Request::from_str("http://domain-1.com/a"));
-        assert_eq!(queue.pop().unwrap().url.as_str(), "http://domain-1.com/c");
+        assert!(!queue.is_empty());
+        // Rules from the redirect target apply to the original domain
+        queue.set_robots(&redirect, RobotsTxt::parse("User-agent: *\nDisallow: /a", "crawler"));
+        queue.decr_pending(&redirect);
+        assert_eq!(queue.pop().unwrap().url.as_str(), "http://domain-1.com/b");
         assert_eq!(queue.pop(), None);
+        assert_eq!(queue.get_n_robots_disallowed(), 1);
     }
 
     #[test]
-    fn test_sampling() {
-        // Run with $ cargo test test_sampling -- --nocapture
-        let mut queue = request_queue(3);
+    fn test_download_delay() {
+        let mut settings = Settings::default();
+        settings.obey_robots_txt = false;
+        settings.download_delay = Duration::from_millis(50);
+        settings.randomize_download_delay = false;
+        let mut queue = RequestQueue::new(&settings);
         queue.push(Request::from_str("http://domain-1.com/a"));
         queue.push(Request::from_str("http://domain-1.com/b"));
-        queue.push(Request::from_str("http://domain-1.com/c"));
         queue.push(Request::from_str("http://domain-2.com/a"));
-        queue.push(Request::from_str("http://domain-2.com/b"));
-        queue.push(Request::from_str("http://domain-2.com/c"));
-        while let Some(request) = queue.pop() {
-            println!("{:?}", request);
+        assert!(queue.pop().is_some());
+        assert!(queue.pop().is_some());
+        // Both domains are delayed now
+        assert_eq!(queue.pop(), None);
+        assert!(queue.time_to_next_ready().unwrap() <= Duration::from_millis(50));
+        queue.decr_pending(&Request::from_str("http://domain-1.com/a"));
+        queue.decr_pending(&Request::from_str("http://domain-2.com/a"));
+        assert_eq!(queue.is_empty(), false);
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(queue.pop().unwrap().url.as_str(), "http://domain-1.com/b");
+        queue.decr_pending(&Request::from_str("http://domain-1.com/b"));
+        assert_eq!(queue.is_empty(), true);
+    }
+
+    #[test]
+    fn test_get_delay() {
+        use std::rc::Rc;
+        use super::{RobotsState, get_delay};
+
+        let robots = RobotsState::Known(Rc::new(
+            RobotsTxt::parse("User-agent: *\nCrawl-delay: 2", "crawler")));
+        let second = Duration::from_secs(1);
+        for _ in 0..100 {
+            // Crawl-delay is a lower bound that is not randomized
+            assert_eq!(get_delay(second, true, &robots), Duration::from_secs(2));
+            let delay = get_delay(second, true, &RobotsState::Unknown);
+            assert!(delay >= Duration::from_millis(500) && delay <= Duration::from_millis(1500));
         }
+        assert_eq!(get_delay(Duration::from_secs(3), false, &robots), Duration::from_secs(3));
+        assert_eq!(get_delay(Duration::from_secs(0), true, &RobotsState::Unknown),
+                   Duration::from_secs(0));
     }
 
     #[test]
-    fn test_duplicates() {
-        // Run with $ cargo test test_sampling -- --nocapture
-        let mut queue = request_queue(3);
-        queue.push(Request::from_str("http://domain-1.com/a"));
-        queue.push(Request::from_str("http://domain-1.com/a"));
-        assert!(queue.pop().is_some());
+    fn test_max_depth() {
+        let mut settings = Settings::default();
+        settings.obey_robots_txt = false;
+        settings.max_depth = Some(1);
+        let mut queue = RequestQueue::new(&settings);
+        let seed = Request::from_str("http://domain-1.com/");
+        let child = seed.follow("http://domain-1.com/a".parse().unwrap());
+        let grandchild = child.follow("http://domain-1.com/b".parse().unwrap());
+        assert_eq!(grandchild.depth, 2);
+        queue.push(seed);
+        queue.push(child);
+        queue.push(grandchild);
+        assert_eq!(queue.pop().unwrap().depth, 0);
+        assert_eq!(queue.pop().unwrap().depth, 1);
         assert_eq!(queue.pop(), None);
     }
+
+    #[test]
+    fn test_restore() {
+        for mut queue in request_queues(2) {
+            queue.push(Request::from_str("http://domain-1.com/a"));
+            queue.push(Request::from_str("http://domain-1.com/b"));
+            queue.push(Request::from_str("http://domain-2.com/a"));
+            let in_flight = queue.pop().unwrap();
+            let mut settings = Settings::default();
+            settings.obey_robots_txt = false;
+            let mut urls: Vec<_> = queue.get_requests().unwrap().iter()
+                .map(|request| request.url.as_str().to_owned()).collect();
+            urls.sort();
+            assert_eq!(urls, vec!["http://domain-1.com/a", "http://domain-1.com/b",
+                                  "http://domain-2.com/a"]);
+            let requests = queue.get_requests().unwrap();
+            let mut seen_requests = new_seen_set(&settings.dedupe_storage);
+            for request in &requests {
+                seen_requests.insert(request.get_fingerprint());
+            }
+            let mut restored = RequestQueue::restore(&settings, seen_requests, requests);
+            assert_eq!(restored.get_n_pending(), 0);
+            // In flight request is restored, and is already seen
+            restored.push(in_flight);
+            let mut popped = Vec::new();
+            while let Some(request) = restored.pop() {
+                popped.push(request.url.as_str().to_owned());
+            }
+            popped.sort();
+            assert_eq!(popped, vec!["http://domain-1.com/a", "http://domain-1.com/b",
+                                    "http://domain-2.com/a"]);
+        }
+    }
+
+    #[test]
+    fn test_restore_canonicalize() {
+        let mut settings = Settings::default();
+        settings.obey_robots_txt = false;
+        settings.canonicalize.strip_trailing_slash = true;
+        let mut queue = RequestQueue::new(&settings);
+        queue.push(Request::from_str("http://domain-1.com/a/"));
+        queue.push(Request::from_str("http://domain-1.com/b/"));
+        assert_eq!(queue.pop().unwrap().url.as_str(), "http://domain-1.com/a/");
+        // In flight request is saved too
+        let requests = queue.get_requests().unwrap();
+        assert_eq!(requests.len(), 2);
+        let mut seen_requests = new_seen_set(&settings.dedupe_storage);
+        for request in &requests {
+            seen_requests.insert(request.get_fingerprint_with(&settings.canonicalize));
+        }
+        let mut restored = RequestQueue::restore(&settings, seen_requests, requests);
+        // Same canonical urls as the restored requests
+        restored.push(Request::from_str("http://domain-1.com/a"));
+        restored.push(Request::from_str("http://domain-1.com/b"));
+        let mut popped = Vec::new();
+        while let Some(request) = restored.pop() {
+            popped.push(request);
+        }
+        let mut urls: Vec<_> = popped.iter().map(|r| r.url.as_str().to_owned()).collect();
+        urls.sort();
+        assert_eq!(urls, vec!["http://domain-1.com/a/", "http://domain-1.com/b/"]);
+        // In flight request is found by its canonical fingerprint
+        restored.decr_pending(&Request::from_str("http://domain-1.com/a"));
+        assert_eq!(restored.get_requests().unwrap().len(), 1);
+    }
 }
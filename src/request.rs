@@ -3,34 +3,160 @@ use std::hash::Hasher;
 use std::hash::SipHasher;
 
 use hyper::Url;
+use rustc_serialize::json;
+
+use canonicalize::{CanonicalizeOptions, canonicalize_url};
+use scope::registered_domain;
 
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Request {
-    pub url: Url
+    pub url: Url,
+    /// Number of hops from the seed
+    pub depth: u32,
+    /// Redirects that led to this request, starting from the originally requested url
+    pub redirect_chain: Vec<Redirect>,
+    /// Registered domain of the seed this request originates from
+    pub seed_domain: String,
+    /// Number of consecutive hops outside of seed_domain
+    pub offsite_hops: u32,
+    /// Page where the link to this request was found
+    pub referrer: Option<Url>,
+    pub is_robots_txt: bool,
+}
+
+/// A redirect response received for url.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redirect {
+    pub url: Url,
+    /// 301, 302, 303, 307 or 308: 307 and 308 require the method to be preserved
+    pub status: u16,
+}
+
+/// Serializable representation of Request.
+#[derive(RustcEncodable, RustcDecodable)]
+struct RequestRecord {
+    url: String,
+    depth: u32,
+    redirect_chain: Vec<(String, u16)>,
+    seed_domain: String,
+    offsite_hops: u32,
+    referrer: Option<String>,
 }
 
 impl Request {
     pub fn new(url: Url) -> Self {
-        Request { url: url }
+        let seed_domain = registered_domain(&url);
+        Request {
+            url: url,
+            depth: 0,
+            redirect_chain: Vec::new(),
+            seed_domain: seed_domain,
+            offsite_hops: 0,
+            referrer: None,
+            is_robots_txt: false,
+        }
+    }
+
+    /// Request for a link found on the page of this request.
+    pub fn follow(&self, url: Url) -> Self {
+        self.child(url, Vec::new())
+    }
+
+    /// Request for the redirect target, when this request got a redirect status.
+    pub fn redirect(&self, url: Url, status: u16) -> Self {
+        let mut redirect_chain = self.redirect_chain.clone();
+        redirect_chain.push(Redirect { url: self.url.clone(), status: status });
+        let mut request = self.child(url, redirect_chain);
+        request.referrer = self.referrer.clone();
+        request.is_robots_txt = self.is_robots_txt;
+        request
+    }
+
+    /// Request for robots.txt of the host of the given url.
+    pub fn robots_txt(url: &Url) -> Option<Self> {
+        url.join("/robots.txt").ok().map(|url| {
+            let mut request = Request::new(url);
+            request.is_robots_txt = true;
+            request
+        })
+    }
+
+    fn child(&self, url: Url, redirect_chain: Vec<Redirect>) -> Self {
+        let offsite_hops = if registered_domain(&url) == self.seed_domain {
+            0
+        } else {
+            self.offsite_hops + 1
+        };
+        Request {
+            url: url,
+            depth: self.depth + 1,
+            redirect_chain: redirect_chain,
+            seed_domain: self.seed_domain.clone(),
+            offsite_hops: offsite_hops,
+            referrer: Some(self.url.clone()),
+            is_robots_txt: false,
+        }
+    }
+
+    pub fn n_redirects(&self) -> usize {
+        self.redirect_chain.len()
+    }
+
+    /// Url that was requested before following any redirects.
+    pub fn original_url(&self) -> &Url {
+        self.redirect_chain.first().map(|redirect| &redirect.url).unwrap_or(&self.url)
     }
 
     pub fn from_str(url: &str) -> Self {
         Request::new(url.parse().unwrap())
     }
 
+    /// Serialize request into a single line of JSON (robots.txt requests are not supported).
+    pub fn to_json(&self) -> String {
+        json::encode(&RequestRecord {
+            url: self.url.as_str().to_owned(),
+            depth: self.depth,
+            redirect_chain: self.redirect_chain.iter()
+                .map(|redirect| (redirect.url.as_str().to_owned(), redirect.status))
+                .collect(),
+            seed_domain: self.seed_domain.clone(),
+            offsite_hops: self.offsite_hops,
+            referrer: self.referrer.as_ref().map(|url| url.as_str().to_owned()),
+        }).unwrap()
+    }
+
+    pub fn from_json(line: &str) -> Option<Self> {
+        let record: RequestRecord = match json::decode(line) {
+            Ok(record) => record,
+            Err(_) => return None,
+        };
+        let mut redirect_chain = Vec::new();
+        for (url, status) in record.redirect_chain {
+            match url.parse() {
+                Ok(url) => redirect_chain.push(Redirect { url: url, status: status }),
+                Err(_) => return None,
+            }
+        }
+        let referrer = record.referrer.and_then(|url| url.parse().ok());
+        record.url.parse().ok().map(|url| Request {
+            url: url,
+            depth: record.depth,
+            redirect_chain: redirect_chain,
+            seed_domain: record.seed_domain,
+            offsite_hops: record.offsite_hops,
+            referrer: referrer,
+            is_robots_txt: false,
+        })
+    }
+
     pub fn get_fingerprint(&self) -> u64 {
+        self.get_fingerprint_with(&CanonicalizeOptions::default())
+    }
+
+    pub fn get_fingerprint_with(&self, options: &CanonicalizeOptions) -> u64 {
         let mut hasher = SipHasher::new();
-        let ref url = self.url;
-        let canonical_url = format!(
-            "{}://{}:{}{}?{}",
-            url.scheme(),
-            url.host_str().unwrap_or(""),
-            url.port_or_known_default().unwrap_or(0),
-            url.path(),
-            url.query().unwrap_or("")); // TODO - canonicalize query
-        // fragment is not included
-        canonical_url.hash(&mut hasher);
+        canonicalize_url(&self.url, options).hash(&mut hasher);
         hasher.finish()
     }
 }
@@ -38,6 +164,7 @@ impl Request {
 
 #[cfg(test)]
 mod tests {
+    use canonicalize::CanonicalizeOptions;
     use super::*;
 
     fn fp(url: &str) -> u64 {
@@ -55,5 +182,39 @@ mod tests {
         assert_eq!(fp("http://a.com/b#foo"), fp("http://a.com/b"));
         assert!(fp("http://a.com/b") != fp("http://a.com/b?a=1"));
         assert_eq!(fp("http://a.com/b"), fp("http://a.com/b?"));
+        assert_eq!(fp("http://a.com/b?a=1&b=2"), fp("http://a.com/b?b=2&a=1"));
+        assert_eq!(fp("http://a.com:80/%7e"), fp("http://A.com/~"));
+        assert!(fp("http://a.com/b/") != fp("http://a.com/b"));
+        let options = CanonicalizeOptions { strip_trailing_slash: true, strip_www: true };
+        assert_eq!(Request::from_str("http://www.a.com/b/").get_fingerprint_with(&options),
+                   Request::from_str("http://a.com/b").get_fingerprint_with(&options));
+    }
+
+    #[test]
+    fn test_redirect() {
+        let request = Request::from_str("http://a.com/");
+        let redirected = request
+            .redirect("https://a.com/".parse().unwrap(), 301)
+            .redirect("https://www.a.com/".parse().unwrap(), 307);
+        assert_eq!(redirected.n_redirects(), 2);
+        assert_eq!(redirected.depth, 2);
+        assert_eq!(redirected.original_url().as_str(), "http://a.com/");
+        assert_eq!(redirected.redirect_chain[1],
+                   Redirect { url: "https://a.com/".parse().unwrap(), status: 307 });
+        assert_eq!(redirected.referrer, None);
+        let link = redirected.follow("https://www.a.com/b".parse().unwrap());
+        assert_eq!(link.n_redirects(), 0);
+        assert_eq!(link.referrer, Some("https://www.a.com/".parse().unwrap()));
+        let link_redirected = link.redirect("https://www.a.com/c".parse().unwrap(), 302);
+        assert_eq!(link_redirected.referrer, link.referrer);
+    }
+
+    #[test]
+    fn test_json() {
+        let request = Request::from_str("http://a.com/")
+            .follow("http://b.com/c?d=1".parse().unwrap())
+            .redirect("https://b.com/c?d=1".parse().unwrap(), 301);
+        assert_eq!(Request::from_json(&request.to_json()), Some(request));
+        assert_eq!(Request::from_json("{}"), None);
     }
 }
@@ -1,10 +1,51 @@
+use std::time::{Duration, SystemTime};
+
 use hyper::status::StatusCode;
 use hyper::header::Headers;
+use hyper::version::HttpVersion;
 
 
 #[derive(Debug, Clone)]
 pub struct Response {
+    pub version: HttpVersion,
     pub status: StatusCode,
+    /// Headers describing the decoded body
     pub headers: Headers,
-    pub body: Option<Vec<u8>>
+    /// Headers as received
+    pub raw_headers: Headers,
+    pub body: Option<Vec<u8>>,
+    /// Body as received, if Content-Encoding was decoded (else it is the same as body)
+    pub raw_body: Option<Vec<u8>>,
+    /// When response headers were received
+    pub fetched_at: SystemTime,
+    /// Time from sending the request until the body was read
+    pub download_time: Duration,
+    /// Body size as received, before Content-Encoding was decoded
+    pub wire_size: usize,
+    /// Body size after decoding
+    pub decoded_size: usize,
+    /// Body was not read or was cut at max_body_size
+    pub truncated: bool,
+    /// Media type from Content-Type, or sniffed from the body if it is missing or generic
+    pub media_type: Option<String>,
+}
+
+/// Response with the given status and body as received, for tests.
+#[cfg(test)]
+pub fn test_response(status: StatusCode, body: Option<&[u8]>) -> Response {
+    let size = body.map_or(0, |body| body.len());
+    Response {
+        version: HttpVersion::Http11,
+        status: status,
+        headers: Headers::new(),
+        raw_headers: Headers::new(),
+        body: body.map(|body| body.to_vec()),
+        raw_body: None,
+        fetched_at: SystemTime::now(),
+        download_time: Duration::from_secs(0),
+        wire_size: size,
+        decoded_size: size,
+        truncated: false,
+        media_type: None,
+    }
 }
@@ -0,0 +1,320 @@
+use std::collections::BTreeMap;
+
+use rustc_serialize::json::{self, Json, ToJson};
+
+use microdata::MicrodataItem;
+
+
+/// Crawled page, as written into out.jl.
+#[derive(Debug, Clone, Default, RustcEncodable)]
+pub struct CrawlResult {
+    /// Text of HTML and other textual bodies, base64 of binary ones
+    pub body: String,
+    pub body_base64: bool,
+    /// Final url, after following redirects
+    pub url: String,
+    pub original_url: String,
+    pub redirects: Vec<RedirectRecord>,
+    /// Page where the link to original_url was found
+    pub referrer: Option<String>,
+    pub depth: u32,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub content_type: Option<String>,
+    /// ISO 8601 UTC time when response headers were received
+    pub fetched_at: String,
+    /// Download duration in seconds
+    pub download_time: f64,
+    /// Body length in bytes, before decoding
+    pub body_length: usize,
+    /// Body was cut at max_body_size
+    pub truncated: bool,
+    pub encoding: String,
+    pub encoding_guessed: bool,
+    /// Text is similar to an earlier crawled page
+    pub near_duplicate: bool,
+    pub title: Option<String>,
+    /// Content of meta description tag
+    pub description: Option<String>,
+    /// Content of meta keywords tag
+    pub keywords: Option<String>,
+    /// lang attribute of the html tag
+    pub lang: Option<String>,
+    /// Text of h1 - h6 tags
+    pub headings: Vec<String>,
+    /// Visible text, without tags, scripts and styles
+    pub text: String,
+    /// Parsed application/ld+json scripts
+    pub json_ld: Vec<Json>,
+    /// Parse errors of malformed application/ld+json scripts
+    pub json_ld_errors: Vec<String>,
+    /// og:* meta properties
+    pub opengraph: Vec<(String, String)>,
+    /// twitter:* meta tags
+    pub twitter: Vec<(String, String)>,
+    pub microdata: Vec<MicrodataItem>,
+    /// All links found on the page, including ones that were not followed
+    pub links: Vec<LinkRecord>,
+}
+
+#[derive(Debug, Clone, Default, RustcEncodable)]
+pub struct RedirectRecord {
+    pub url: String,
+    pub status: u16,
+}
+
+#[derive(Debug, Clone, Default, RustcEncodable)]
+pub struct LinkRecord {
+    pub url: String,
+    /// Anchor text
+    pub text: String,
+}
+
+/// Optional fields of CrawlResult written into out.jl, url and depth are always written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResultFields {
+    /// Raw HTML (body and body_base64), can be disabled if only text and metadata are needed
+    pub body: bool,
+    /// body_length and truncated
+    pub body_length: bool,
+    pub content_type: bool,
+    pub download_time: bool,
+    /// encoding and encoding_guessed
+    pub encoding: bool,
+    pub fetched_at: bool,
+    /// Values of repeated headers are written as arrays
+    pub headers: ResultHeaders,
+    pub links: bool,
+    /// title, description, keywords, lang and headings
+    pub metadata: bool,
+    pub near_duplicate: bool,
+    /// original_url and redirects
+    pub redirects: bool,
+    pub referrer: bool,
+    pub status: bool,
+    /// json_ld, json_ld_errors, opengraph, twitter and microdata
+    pub structured_data: bool,
+    pub text: bool,
+}
+
+impl Default for ResultFields {
+    fn default() -> Self {
+        ResultFields {
+            body: true,
+            body_length: true,
+            content_type: true,
+            download_time: true,
+            encoding: true,
+            fetched_at: true,
+            headers: ResultHeaders::All,
+            links: false,
+            metadata: true,
+            near_duplicate: true,
+            redirects: true,
+            referrer: true,
+            status: true,
+            structured_data: true,
+            text: true,
+        }
+    }
+}
+
+/// Which response headers are written.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResultHeaders {
+    None,
+    All,
+    /// Only headers with these names (case insensitive)
+    Only(Vec<String>),
+}
+
+impl ResultHeaders {
+    fn includes(&self, name: &str) -> bool {
+        match *self {
+            ResultHeaders::None => false,
+            ResultHeaders::All => true,
+            ResultHeaders::Only(ref names) =>
+                names.iter().any(|header| header.to_lowercase() == name.to_lowercase()),
+        }
+    }
+}
+
+impl CrawlResult {
+    /// Serialize into a single line of JSON, with only selected fields.
+    pub fn to_json(&self, fields: &ResultFields) -> String {
+        let mut obj = BTreeMap::new();
+        obj.insert("url".to_owned(), self.url.to_json());
+        obj.insert("depth".to_owned(), self.depth.to_json());
+        if fields.body {
+            obj.insert("body".to_owned(), self.body.to_json());
+            obj.insert("body_base64".to_owned(), self.body_base64.to_json());
+        }
+        if fields.body_length {
+            obj.insert("body_length".to_owned(), (self.body_length as u64).to_json());
+            obj.insert("truncated".to_owned(), self.truncated.to_json());
+        }
+        if fields.content_type {
+            obj.insert("content_type".to_owned(), self.content_type.to_json());
+        }
+        if fields.download_time {
+            obj.insert("download_time".to_owned(), self.download_time.to_json());
+        }
+        if fields.encoding {
+            obj.insert("encoding".to_owned(), self.encoding.to_json());
+            obj.insert("encoding_guessed".to_owned(), self.encoding_guessed.to_json());
+        }
+        if fields.fetched_at {
+            obj.insert("fetched_at".to_owned(), self.fetched_at.to_json());
+        }
+        if fields.headers != ResultHeaders::None {
+            let headers: Vec<_> = self.headers.iter()
+                .filter(|&&(ref name, _)| fields.headers.includes(name))
+                .cloned()
+                .collect();
+            obj.insert("headers".to_owned(), pairs_to_json(&headers));
+        }
+        if fields.links {
+            let links = self.links.iter().map(|link| {
+                let mut link_obj = BTreeMap::new();
+                link_obj.insert("url".to_owned(), link.url.to_json());
+                link_obj.insert("text".to_owned(), link.text.to_json());
+                Json::Object(link_obj)
+            }).collect();
+            obj.insert("links".to_owned(), Json::Array(links));
+        }
+        if fields.metadata {
+            obj.insert("title".to_owned(), self.title.to_json());
+            obj.insert("description".to_owned(), self.description.to_json());
+            obj.insert("keywords".to_owned(), self.keywords.to_json());
+            obj.insert("lang".to_owned(), self.lang.to_json());
+            obj.insert("headings".to_owned(), self.headings.to_json());
+        }
+        if fields.near_duplicate {
+            obj.insert("near_duplicate".to_owned(), self.near_duplicate.to_json());
+        }
+        if fields.redirects {
+            obj.insert("original_url".to_owned(), self.original_url.to_json());
+            let redirects = self.redirects.iter().map(|redirect| {
+                let mut redirect_obj = BTreeMap::new();
+                redirect_obj.insert("url".to_owned(), redirect.url.to_json());
+                redirect_obj.insert("status".to_owned(), redirect.status.to_json());
+                Json::Object(redirect_obj)
+            }).collect();
+            obj.insert("redirects".to_owned(), Json::Array(redirects));
+        }
+        if fields.referrer {
+            obj.insert("referrer".to_owned(), self.referrer.to_json());
+        }
+        if fields.status {
+            obj.insert("status".to_owned(), self.status.to_json());
+        }
+        if fields.structured_data {
+            obj.insert("json_ld".to_owned(), self.json_ld.to_json());
+            obj.insert("json_ld_errors".to_owned(), self.json_ld_errors.to_json());
+            obj.insert("opengraph".to_owned(), pairs_to_json(&self.opengraph));
+            obj.insert("twitter".to_owned(), pairs_to_json(&self.twitter));
+            obj.insert("microdata".to_owned(), self.microdata.to_json());
+        }
+        if fields.text {
+            obj.insert("text".to_owned(), self.text.to_json());
+        }
+        json::encode(&Json::Object(obj)).unwrap()
+    }
+}
+
+/// Name-value pairs as an object, with values of repeated names (like og:image
+/// or Set-Cookie) collected into arrays.
+fn pairs_to_json(pairs: &[(String, String)]) -> Json {
+    let mut obj = BTreeMap::new();
+    for &(ref name, ref value) in pairs {
+        let json = match obj.remove(name) {
+            None => value.to_json(),
+            Some(Json::Array(mut values)) => {
+                values.push(value.to_json());
+                Json::Array(values)
+            },
+            Some(previous) => Json::Array(vec![previous, value.to_json()]),
+        };
+        obj.insert(name.clone(), json);
+    }
+    Json::Object(obj)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::json::Json;
+    use super::*;
+
+    fn result() -> CrawlResult {
+        CrawlResult {
+            body: "<p>a</p>".to_owned(),
+            url: "http://a.com/b".to_owned(),
+            original_url: "http://a.com/".to_owned(),
+            redirects: vec![RedirectRecord { url: "http://a.com/".to_owned(), status: 301 }],
+            referrer: Some("http://c.com/".to_owned()),
+            depth: 2,
+            status: 200,
+            headers: vec![("Content-Type".to_owned(), "text/html".to_owned()),
+                          ("Server".to_owned(), "nginx".to_owned()),
+                          ("Set-Cookie".to_owned(), "a=1".to_owned()),
+                          ("Set-Cookie".to_owned(), "b=2".to_owned())],
+            content_type: Some("text/html".to_owned()),
+            fetched_at: "2016-07-01T12:00:00Z".to_owned(),
+            download_time: 0.5,
+            body_length: 8,
+            encoding: "utf-8".to_owned(),
+            encoding_guessed: true,
+            title: Some("A".to_owned()),
+            lang: Some("en".to_owned()),
+            headings: vec!["B".to_owned()],
+            text: "a".to_owned(),
+            json_ld_errors: vec!["syntax error".to_owned()],
+            opengraph: vec![("og:image".to_owned(), "a.jpg".to_owned()),
+                            ("og:image".to_owned(), "b.jpg".to_owned()),
+                            ("og:title".to_owned(), "A".to_owned())],
+            links: vec![LinkRecord { url: "http://d.com/".to_owned(), text: "D".to_owned() }],
+            .. CrawlResult::default()
+        }
+    }
+
+    #[test]
+    fn test_all_fields() {
+        let json = Json::from_str(&result().to_json(&ResultFields::default())).unwrap();
+        assert_eq!(json.as_object().unwrap().len(), 28);
+        assert_eq!(json.find("status").unwrap().as_u64(), Some(200));
+        assert_eq!(json.find_path(&["headers", "Server"]).unwrap().as_string(), Some("nginx"));
+        assert_eq!(json.find_path(&["headers", "Set-Cookie"]).unwrap().to_string(),
+                   "[\"a=1\",\"b=2\"]");
+        assert_eq!(json.find("referrer").unwrap().as_string(), Some("http://c.com/"));
+        assert_eq!(json.find_path(&["redirects"]).unwrap().as_array().unwrap().len(), 1);
+        assert_eq!(json.find("opengraph").unwrap().to_string(),
+                   "{\"og:image\":[\"a.jpg\",\"b.jpg\"],\"og:title\":\"A\"}");
+    }
+
+    #[test]
+    fn test_selected_fields() {
+        let fields = ResultFields {
+            body: false,
+            body_length: false,
+            content_type: true,
+            download_time: false,
+            encoding: false,
+            fetched_at: false,
+            headers: ResultHeaders::Only(vec!["server".to_owned()]),
+            links: true,
+            metadata: false,
+            near_duplicate: false,
+            redirects: false,
+            referrer: false,
+            status: true,
+            structured_data: false,
+            text: false,
+        };
+        assert_eq!(result().to_json(&fields),
+                   "{\"content_type\":\"text/html\",\"depth\":2,\
+                    \"headers\":{\"Server\":\"nginx\"},\
+                    \"links\":[{\"text\":\"D\",\"url\":\"http://d.com/\"}],\
+                    \"status\":200,\"url\":\"http://a.com/b\"}");
+    }
+}
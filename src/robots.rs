@@ -0,0 +1,297 @@
+use std::time::Duration;
+
+use hyper::Url;
+
+use response::Response;
+
+
+/// Robots.txt redirects are followed up to this many times (RFC 9309 asks for at least five),
+/// after that robots.txt is treated as unavailable.
+pub const MAX_REDIRECTS: usize = 5;
+
+/// Rules from robots.txt that apply to our user agent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RobotsTxt {
+    rules: Vec<Rule>,
+    crawl_delay: Option<Duration>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Rule {
+    allow: bool,
+    pattern: String,
+}
+
+struct Group {
+    agents: Vec<String>,
+    rules: Vec<Rule>,
+    crawl_delay: Option<Duration>,
+}
+
+/// How robots.txt fetch ended, used for stats.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RobotsOutcome {
+    /// Got 2xx and parsed the rules
+    Fetched,
+    /// Got 3xx that was not followed (no Location or too many redirects),
+    /// robots.txt is unavailable and everything is allowed
+    Redirect,
+    /// Got 4xx (or some other non-error status), everything is allowed
+    ClientError,
+    /// Got 5xx, everything is disallowed
+    ServerError,
+    /// Timeout or connection error, everything is disallowed
+    Failed,
+}
+
+impl RobotsTxt {
+    pub fn allow_all() -> Self {
+        RobotsTxt { rules: Vec::new(), crawl_delay: None }
+    }
+
+    pub fn disallow_all() -> Self {
+        RobotsTxt {
+            rules: vec![Rule { allow: false, pattern: "/".to_owned() }],
+            crawl_delay: None,
+        }
+    }
+
+    /// Parse robots.txt, keeping only the group that matches user_agent best
+    /// (or the "*" group if no other group matches).
+    pub fn parse(text: &str, user_agent: &str) -> Self {
+        let user_agent = user_agent.to_lowercase();
+        let mut groups: Vec<Group> = Vec::new();
+        let mut in_agents = false;
+        for line in text.lines() {
+            let line = match line.find('#') {
+                Some(idx) => &line[..idx],
+                None => line,
+            };
+            let (key, value) = match line.find(':') {
+                Some(idx) => (line[..idx].trim().to_lowercase(), line[idx + 1..].trim()),
+                None => continue,
+            };
+            if key == "user-agent" {
+                if !in_agents {
+                    groups.push(Group { agents: Vec::new(), rules: Vec::new(), crawl_delay: None });
+                    in_agents = true;
+                }
+                if let Some(group) = groups.last_mut() {
+                    group.agents.push(value.to_lowercase());
+                }
+                continue;
+            }
+            in_agents = false;
+            if let Some(group) = groups.last_mut() {
+                match key.as_str() {
+                    "allow" | "disallow" if !value.is_empty() => {
+                        group.rules.push(Rule {
+                            allow: key == "allow",
+                            pattern: value.to_owned(),
+                        });
+                    },
+                    "crawl-delay" => {
+                        if let Ok(delay) = value.parse::<f64>() {
+                            if delay >= 0. {
+                                group.crawl_delay = Some(Duration::from_millis(
+                                    (delay * 1000.) as u64));
+                            }
+                        }
+                    },
+                    _ => ()
+                }
+            }
+        }
+        // The most specific (longest) matching agent wins, "*" is the fallback.
+        let specificity = |agent: &String| {
+            if agent == "*" {
+                Some(0)
+            } else if !agent.is_empty() && user_agent.contains(agent.as_str()) {
+                Some(agent.len())
+            } else {
+                None
+            }
+        };
+        let best = groups.iter()
+            .filter_map(|group| group.agents.iter().filter_map(&specificity).max())
+            .max();
+        let mut robots = RobotsTxt::allow_all();
+        if let Some(best) = best {
+            for group in groups {
+                if group.agents.iter().filter_map(&specificity).max() == Some(best) {
+                    robots.rules.extend(group.rules);
+                    if robots.crawl_delay.is_none() {
+                        robots.crawl_delay = group.crawl_delay;
+                    }
+                }
+            }
+        }
+        robots
+    }
+
+    /// Build rules from robots.txt response, or from the lack of it.
+    /// Redirects that can be followed should be followed before calling this.
+    pub fn from_response(response: &Option<Response>, user_agent: &str)
+            -> (Self, RobotsOutcome) {
+        match *response {
+            Some(ref response) => {
+                if response.status.is_success() {
+                    let robots = match response.body {
+                        Some(ref body) => RobotsTxt::parse(
+                            &String::from_utf8_lossy(body), user_agent),
+                        None => RobotsTxt::allow_all(),
+                    };
+                    (robots, RobotsOutcome::Fetched)
+                } else if response.status.is_redirection() {
+                    (RobotsTxt::allow_all(), RobotsOutcome::Redirect)
+                } else if response.status.is_server_error() {
+                    (RobotsTxt::disallow_all(), RobotsOutcome::ServerError)
+                } else {
+                    (RobotsTxt::allow_all(), RobotsOutcome::ClientError)
+                }
+            },
+            None => (RobotsTxt::disallow_all(), RobotsOutcome::Failed)
+        }
+    }
+
+    pub fn is_allowed(&self, url: &Url) -> bool {
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_owned(),
+        };
+        if path == "/robots.txt" {
+            return true;
+        }
+        // The longest matching rule wins, allow wins if there is a tie.
+        let mut best: Option<&Rule> = None;
+        for rule in &self.rules {
+            if pattern_matches(&rule.pattern, &path) {
+                best = match best {
+                    Some(best) if best.pattern.len() > rule.pattern.len() => Some(best),
+                    Some(best) if best.pattern.len() == rule.pattern.len() && best.allow =>
+                        Some(best),
+                    _ => Some(rule),
+                };
+            }
+        }
+        best.map(|rule| rule.allow).unwrap_or(true)
+    }
+
+    pub fn crawl_delay(&self) -> Option<Duration> {
+        self.crawl_delay
+    }
+}
+
+/// Match path against a robots.txt pattern, where "*" matches any sequence
+/// and "$" at the end anchors the pattern to the end of the path.
+fn pattern_matches(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = if pattern.ends_with('$') {
+        (&pattern[..pattern.len() - 1], true)
+    } else {
+        (pattern, false)
+    };
+    let parts: Vec<&str> = pattern.split('*').collect();
+    let mut rest = path;
+    for (idx, part) in parts.iter().enumerate() {
+        if idx == 0 {
+            if !rest.starts_with(part) {
+                return false;
+            }
+            rest = &rest[part.len()..];
+        } else if idx == parts.len() - 1 && anchored {
+            return rest.ends_with(part);
+        } else {
+            match rest.find(part) {
+                Some(pos) => rest = &rest[pos + part.len()..],
+                None => return false,
+            }
+        }
+    }
+    !anchored || rest.is_empty()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn allowed(robots: &RobotsTxt, url: &str) -> bool {
+        robots.is_allowed(&url.parse().unwrap())
+    }
+
+    #[test]
+    fn test_groups() {
+        let text = "# comment\n\
+                    User-agent: *\n\
+                    Disallow: /private\n\
+                    \n\
+                    User-agent: crawler\n\
+                    User-agent: other\n\
+                    Disallow: /secret # inline comment\n\
+                    Crawl-delay: 1.5\n";
+        let robots = RobotsTxt::parse(text, "crawler");
+        assert!(allowed(&robots, "http://a.com/private"));
+        assert!(!allowed(&robots, "http://a.com/secret/a"));
+        assert_eq!(robots.crawl_delay(), Some(Duration::from_millis(1500)));
+        let robots = RobotsTxt::parse(text, "somebot");
+        assert!(!allowed(&robots, "http://a.com/private"));
+        assert!(allowed(&robots, "http://a.com/secret/a"));
+        assert_eq!(robots.crawl_delay(), None);
+    }
+
+    #[test]
+    fn test_allow_disallow() {
+        let text = "User-agent: *\n\
+                    Disallow: /a\n\
+                    Allow: /a/b\n\
+                    Disallow:\n";
+        let robots = RobotsTxt::parse(text, "crawler");
+        assert!(allowed(&robots, "http://a.com/"));
+        assert!(!allowed(&robots, "http://a.com/a"));
+        assert!(!allowed(&robots, "http://a.com/a/c"));
+        assert!(allowed(&robots, "http://a.com/a/b"));
+        assert!(allowed(&robots, "http://a.com/a/b/c"));
+        assert!(allowed(&robots, "http://a.com/robots.txt"));
+    }
+
+    #[test]
+    fn test_wildcards() {
+        let text = "User-agent: *\n\
+                    Disallow: /*.pdf$\n\
+                    Disallow: /*?session=\n";
+        let robots = RobotsTxt::parse(text, "crawler");
+        assert!(!allowed(&robots, "http://a.com/doc.pdf"));
+        assert!(!allowed(&robots, "http://a.com/a/b/doc.pdf"));
+        assert!(allowed(&robots, "http://a.com/doc.pdf.html"));
+        assert!(!allowed(&robots, "http://a.com/page?session=1"));
+        assert!(allowed(&robots, "http://a.com/page?id=1"));
+    }
+
+    #[test]
+    fn test_from_response() {
+        use hyper::status::StatusCode;
+        use response::{Response, test_response};
+
+        let response = |status: StatusCode, body: &[u8]| Some(test_response(status, Some(body)));
+        let outcome = |response: Option<Response>| RobotsTxt::from_response(&response, "crawler");
+        let robots = RobotsTxt::parse("User-agent: *\nDisallow: /a", "crawler");
+        assert_eq!(outcome(response(StatusCode::Ok, b"User-agent: *\nDisallow: /a")),
+                   (robots, RobotsOutcome::Fetched));
+        assert_eq!(outcome(response(StatusCode::MovedPermanently, b"")),
+                   (RobotsTxt::allow_all(), RobotsOutcome::Redirect));
+        assert_eq!(outcome(response(StatusCode::NotFound, b"")),
+                   (RobotsTxt::allow_all(), RobotsOutcome::ClientError));
+        assert_eq!(outcome(response(StatusCode::ServiceUnavailable, b"")),
+                   (RobotsTxt::disallow_all(), RobotsOutcome::ServerError));
+        assert_eq!(outcome(None), (RobotsTxt::disallow_all(), RobotsOutcome::Failed));
+    }
+
+    #[test]
+    fn test_disallow_all() {
+        let robots = RobotsTxt::disallow_all();
+        assert!(!allowed(&robots, "http://a.com/"));
+        assert!(!allowed(&robots, "http://a.com/foo"));
+        assert!(allowed(&RobotsTxt::allow_all(), "http://a.com/foo"));
+    }
+}
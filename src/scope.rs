@@ -0,0 +1,101 @@
+use hyper::Url;
+use url::Host;
+
+use request::Request;
+
+
+/// Which links (and redirects) are followed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scope {
+    /// Follow all links
+    Any,
+    /// Follow links only to the same host as the referring page
+    SameHost,
+    /// Follow links only within the registered domain of the seed
+    SameDomain,
+    /// Allow up to N consecutive hops outside of the registered domain of the seed
+    OffsiteHops(u32),
+}
+
+impl Scope {
+    /// Check if request made from referrer page is within the scope.
+    pub fn allows(&self, referrer: &Request, request: &Request) -> bool {
+        match *self {
+            Scope::Any => true,
+            Scope::SameHost => referrer.url.host_str() == request.url.host_str(),
+            Scope::SameDomain => request.offsite_hops == 0,
+            Scope::OffsiteHops(max_hops) => request.offsite_hops <= max_hops,
+        }
+    }
+}
+
+const SECOND_LEVEL_LABELS: &'static [&'static str] = &[
+    "ac", "co", "com", "edu", "go", "gob", "gov", "mil", "ne", "net", "or", "org"];
+
+/// Registered domain of the url host, e.g. "example.co.uk" for "www.example.co.uk".
+/// This is a heuristic that does not use the public suffix list:
+/// generic second level labels under country code TLDs are treated as suffixes.
+pub fn registered_domain(url: &Url) -> String {
+    match url.host() {
+        Some(Host::Domain(domain)) => {
+            let domain = domain.trim_right_matches('.').to_lowercase();
+            let labels: Vec<&str> = domain.split('.').collect();
+            let n_labels = labels.len();
+            let n_suffix_labels = if n_labels >= 3 && labels[n_labels - 1].len() == 2 &&
+                    SECOND_LEVEL_LABELS.iter().any(|&label| label == labels[n_labels - 2]) {
+                2
+            } else {
+                1
+            };
+            if n_labels > n_suffix_labels {
+                labels[n_labels - n_suffix_labels - 1..].join(".")
+            } else {
+                domain.clone()
+            }
+        },
+        Some(host) => host.to_string(),
+        None => "".to_owned(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use request::Request;
+    use super::*;
+
+    fn domain(url: &str) -> String {
+        registered_domain(&url.parse().unwrap())
+    }
+
+    #[test]
+    fn test_registered_domain() {
+        assert_eq!(domain("http://example.com/a"), "example.com");
+        assert_eq!(domain("http://www.example.com/a"), "example.com");
+        assert_eq!(domain("http://a.b.example.com/a"), "example.com");
+        assert_eq!(domain("http://www.example.co.uk/"), "example.co.uk");
+        assert_eq!(domain("http://example.co.uk/"), "example.co.uk");
+        assert_eq!(domain("http://www.example.de/"), "example.de");
+        assert_eq!(domain("http://localhost/"), "localhost");
+        assert_eq!(domain("http://127.0.0.1:8000/"), "127.0.0.1");
+    }
+
+    #[test]
+    fn test_scope() {
+        let seed = Request::from_str("http://www.a.com/");
+        let same_host = seed.follow("http://www.a.com/b".parse().unwrap());
+        let same_domain = seed.follow("http://blog.a.com/".parse().unwrap());
+        let offsite = seed.follow("http://b.com/".parse().unwrap());
+        let offsite_2 = offsite.follow("http://c.com/".parse().unwrap());
+        let back = offsite_2.follow("http://a.com/".parse().unwrap());
+        assert!(Scope::Any.allows(&offsite, &offsite_2));
+        assert!(Scope::SameHost.allows(&seed, &same_host));
+        assert!(!Scope::SameHost.allows(&seed, &same_domain));
+        assert!(Scope::SameDomain.allows(&seed, &same_domain));
+        assert!(!Scope::SameDomain.allows(&seed, &offsite));
+        assert!(Scope::OffsiteHops(1).allows(&seed, &offsite));
+        assert!(!Scope::OffsiteHops(1).allows(&offsite, &offsite_2));
+        assert!(Scope::OffsiteHops(2).allows(&offsite, &offsite_2));
+        assert!(Scope::SameDomain.allows(&offsite_2, &back));
+    }
+}
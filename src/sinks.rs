@@ -0,0 +1,158 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use csv;
+
+use output::RotatingWriter;
+use request::Request;
+use response::Response;
+use result::{CrawlResult, ResultFields};
+
+
+/// Receives crawl outcomes. Any number of sinks can be used at the same time.
+pub trait Sink {
+    /// Called for every finished request, response is None if the request failed.
+    fn write_response(&mut self, _request: &Request, _response: &Option<Response>) {
+    }
+
+    /// Called for every crawled page.
+    fn write_result(&mut self, _result: &CrawlResult) {
+    }
+
+    /// Called every checkpoint_interval, after crawl state is saved into the job directory
+    /// (if it is set), so that outputs are usable if the crawl is killed.
+    fn checkpoint(&mut self) {
+    }
+
+    /// Called when the crawl is finished.
+    fn close(&mut self) {
+    }
+}
+
+/// Writes crawl results in JSON-lines format.
+pub struct JsonLinesWriter {
+    output: RotatingWriter,
+    fields: ResultFields,
+}
+
+impl JsonLinesWriter {
+    pub fn new(output: RotatingWriter, fields: &ResultFields) -> Self {
+        JsonLinesWriter {
+            output: output,
+            fields: fields.clone(),
+        }
+    }
+}
+
+impl Sink for JsonLinesWriter {
+    fn write_result(&mut self, result: &CrawlResult) {
+        let line = format!("{}\n", result.to_json(&self.fields));
+        self.output.write_record(line.as_bytes());
+    }
+
+    fn checkpoint(&mut self) {
+        self.output.flush();
+    }
+
+    fn close(&mut self) {
+        self.output.close();
+    }
+}
+
+/// Writes timestamp, status, url and depth of every request in CSV format.
+pub struct ResponseLogWriter {
+    output: RotatingWriter,
+}
+
+impl ResponseLogWriter {
+    pub fn new(output: RotatingWriter) -> Self {
+        ResponseLogWriter {
+            output: output,
+        }
+    }
+}
+
+impl Sink for ResponseLogWriter {
+    fn write_response(&mut self, request: &Request, response: &Option<Response>) {
+        let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(duration) => {
+                let dt = duration.as_secs() as f64 + 1e-9 * duration.subsec_nanos() as f64;
+                format!("{:.6}", dt)
+            },
+            Err(_) => "-".to_owned()
+        };
+        let status = if let &Some(ref response) = response {
+            response.status.to_string()
+        } else {
+            "-".to_string()
+        };
+        let mut record = csv::Writer::from_memory();
+        record.encode((timestamp, status, request.url.as_str(), request.depth)).unwrap();
+        self.output.write_record(record.as_bytes());
+    }
+
+    fn checkpoint(&mut self) {
+        self.output.flush();
+    }
+
+    fn close(&mut self) {
+        self.output.close();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Read;
+
+    use output::{OutputCompression, OutputRotation, RotatingWriter};
+    use result::{CrawlResult, ResultFields, ResultHeaders};
+    use super::*;
+
+    #[test]
+    fn test_json_lines_writer() {
+        let path = env::temp_dir().join("crawler-test-json-lines-writer.jl");
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+        {
+            let fields = ResultFields {
+                headers: ResultHeaders::None,
+                metadata: false,
+                near_duplicate: false,
+                redirects: false,
+                structured_data: false,
+                text: false,
+                .. ResultFields::default()
+            };
+            let mut writer = JsonLinesWriter::new(RotatingWriter::new(
+                path, &OutputRotation::default(), OutputCompression::None), &fields);
+            let result = CrawlResult {
+                body: "<p>a</p>".to_owned(),
+                url: "http://a.com/".to_owned(),
+                original_url: "http://a.com/".to_owned(),
+                status: 200,
+                fetched_at: "2016-07-01T12:00:00Z".to_owned(),
+                download_time: 0.5,
+                body_length: 8,
+                encoding: "utf-8".to_owned(),
+                encoding_guessed: true,
+                text: "a".to_owned(),
+                .. CrawlResult::default()
+            };
+            writer.write_result(&result);
+            writer.write_result(&result);
+            writer.close();
+        }
+        let mut contents = String::new();
+        File::open(path).unwrap().read_to_string(&mut contents).unwrap();
+        fs::remove_file(path).unwrap();
+        let lines: Vec<_> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], "{\"body\":\"<p>a</p>\",\"body_base64\":false,\"body_length\":8,\
+                              \"content_type\":null,\"depth\":0,\"download_time\":0.5,\
+                              \"encoding\":\"utf-8\",\"encoding_guessed\":true,\
+                              \"fetched_at\":\"2016-07-01T12:00:00Z\",\"referrer\":null,\
+                              \"status\":200,\"truncated\":false,\"url\":\"http://a.com/\"}");
+    }
+}
@@ -0,0 +1,402 @@
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::thread;
+use std::time::SystemTime;
+
+use hyper::header::ContentType;
+use hyper::Url;
+use rusqlite::Connection;
+use rusqlite::Error as SqliteError;
+use rusqlite::types::ToSql;
+
+use request::Request;
+use response::Response;
+use result::{CrawlResult, ResultFields};
+use sinks::Sink;
+use warc::format_timestamp;
+
+
+/// Schema migrations, the schema version is the number of applied migrations.
+const MIGRATIONS: &'static [&'static str] = &[
+    "CREATE TABLE hosts (
+         id INTEGER PRIMARY KEY,
+         name TEXT NOT NULL UNIQUE
+     );
+     CREATE TABLE fetches (
+         id INTEGER PRIMARY KEY,
+         timestamp TEXT NOT NULL,
+         url TEXT NOT NULL,
+         host_id INTEGER REFERENCES hosts (id),
+         depth INTEGER NOT NULL,
+         status INTEGER,
+         content_type TEXT,
+         download_time REAL
+     );
+     CREATE INDEX fetches_host_id ON fetches (host_id);
+     CREATE INDEX fetches_status ON fetches (status);
+     CREATE TABLE pages (
+         id INTEGER PRIMARY KEY,
+         url TEXT NOT NULL,
+         original_url TEXT NOT NULL,
+         host_id INTEGER REFERENCES hosts (id),
+         referrer TEXT,
+         depth INTEGER NOT NULL,
+         status INTEGER NOT NULL,
+         content_type TEXT,
+         fetched_at TEXT NOT NULL,
+         download_time REAL NOT NULL,
+         body_length INTEGER NOT NULL,
+         encoding TEXT NOT NULL,
+         encoding_guessed INTEGER NOT NULL,
+         near_duplicate INTEGER NOT NULL,
+         title TEXT,
+         description TEXT,
+         lang TEXT,
+         text TEXT,
+         body TEXT
+     );
+     CREATE INDEX pages_url ON pages (url);
+     CREATE INDEX pages_host_id ON pages (host_id);
+     CREATE INDEX pages_content_type ON pages (content_type);
+     CREATE TABLE headers (
+         page_id INTEGER NOT NULL REFERENCES pages (id),
+         name TEXT NOT NULL,
+         value TEXT NOT NULL
+     );
+     CREATE INDEX headers_page_id ON headers (page_id);
+     CREATE TABLE links (
+         page_id INTEGER NOT NULL REFERENCES pages (id),
+         url TEXT NOT NULL,
+         text TEXT NOT NULL
+     );
+     CREATE INDEX links_page_id ON links (page_id);
+     CREATE INDEX links_url ON links (url);",
+    "ALTER TABLE pages ADD COLUMN truncated INTEGER NOT NULL DEFAULT 0",
+    "ALTER TABLE pages ADD COLUMN body_base64 INTEGER NOT NULL DEFAULT 0",
+];
+
+/// At most this many records are written in one transaction.
+const MAX_BATCH_SIZE: usize = 1000;
+
+/// At most this many records wait to be written, sinks block when the writer falls behind.
+const MAX_QUEUED_RECORDS: usize = 10 * MAX_BATCH_SIZE;
+
+struct Fetch {
+    timestamp: String,
+    url: String,
+    depth: u32,
+    status: Option<u16>,
+    content_type: Option<String>,
+    download_time: Option<f64>,
+}
+
+enum Record {
+    Fetch(Fetch),
+    Page(CrawlResult),
+}
+
+impl Record {
+    fn url(&self) -> &str {
+        match *self {
+            Record::Fetch(ref fetch) => &fetch.url,
+            Record::Page(ref page) => &page.url,
+        }
+    }
+}
+
+/// Writes fetch log, pages and links into an SQLite database.
+/// Records are written in batched transactions in a separate thread.
+pub struct SqliteWriter {
+    sender: Option<mpsc::SyncSender<Record>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl SqliteWriter {
+    /// Open (or create) the database, and migrate it to the latest schema version.
+    pub fn new(path: &str, fields: &ResultFields) -> Result<Self, SqliteError> {
+        let connection = try!(Connection::open(path));
+        try!(migrate(&connection));
+        let (sender, receiver) = mpsc::sync_channel(MAX_QUEUED_RECORDS);
+        let fields = fields.clone();
+        let thread = thread::spawn(move || {
+            let mut database = Database {
+                connection: connection,
+                fields: fields,
+                host_ids: HashMap::new(),
+            };
+            while let Ok(record) = receiver.recv() {
+                let mut batch = vec![record];
+                while batch.len() < MAX_BATCH_SIZE {
+                    match receiver.try_recv() {
+                        Ok(record) => batch.push(record),
+                        Err(_) => break,
+                    }
+                }
+                if let Err(e) = database.write_batch(&batch) {
+                    error!("Error writing {} records to SQLite: {}", batch.len(), e);
+                }
+            }
+        });
+        Ok(SqliteWriter {
+            sender: Some(sender),
+            thread: Some(thread),
+        })
+    }
+
+    fn send(&self, record: Record) {
+        if let Some(ref sender) = self.sender {
+            sender.send(record).expect("SQLite writer thread exited");
+        }
+    }
+}
+
+impl Sink for SqliteWriter {
+    fn write_response(&mut self, request: &Request, response: &Option<Response>) {
+        let timestamp = format_timestamp(SystemTime::now());
+        self.send(Record::Fetch(Fetch {
+            timestamp: timestamp,
+            url: request.url.as_str().to_owned(),
+            depth: request.depth,
+            status: response.as_ref().map(|response| response.status.to_u16()),
+            content_type: response.as_ref()
+                .and_then(|response| response.headers.get::<ContentType>())
+                .map(|content_type| content_type.to_string()),
+            download_time: response.as_ref().map(|response| {
+                response.download_time.as_secs() as f64 +
+                    1e-9 * response.download_time.subsec_nanos() as f64
+            }),
+        }));
+    }
+
+    fn write_result(&mut self, result: &CrawlResult) {
+        self.send(Record::Page(result.clone()));
+    }
+
+    fn close(&mut self) {
+        // Closing the channel makes the thread exit after writing remaining records
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("SQLite writer thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for SqliteWriter {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+fn schema_version(connection: &Connection) -> Result<usize, SqliteError> {
+    try!(connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)"));
+    let version: Option<i64> = try!(connection.query_row(
+        "SELECT MAX(version) FROM schema_version", &[], |row| row.get(0)));
+    Ok(version.unwrap_or(0) as usize)
+}
+
+fn migrate(connection: &Connection) -> Result<(), SqliteError> {
+    let version = try!(schema_version(connection));
+    for (idx, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        info!("Migrating SQLite database to schema version {}", idx + 1);
+        try!(connection.execute_batch(&format!(
+            "BEGIN; {}; INSERT INTO schema_version (version) VALUES ({}); COMMIT;",
+            migration, idx + 1)));
+    }
+    Ok(())
+}
+
+struct Database {
+    connection: Connection,
+    fields: ResultFields,
+    host_ids: HashMap<String, i64>,
+}
+
+impl Database {
+    /// Write records in one transaction. Each record is written under a savepoint,
+    /// so that a record that can not be written is skipped without losing the others.
+    fn write_batch(&mut self, batch: &[Record]) -> Result<(), SqliteError> {
+        try!(self.connection.execute_batch("BEGIN"));
+        let mut written = self.write_records(batch);
+        if written.is_ok() {
+            written = self.connection.execute_batch("COMMIT");
+        }
+        if written.is_err() {
+            self.host_ids.clear();
+            if let Err(e) = self.connection.execute_batch("ROLLBACK") {
+                error!("Error rolling back SQLite transaction: {}", e);
+            }
+        }
+        written
+    }
+
+    fn write_records(&mut self, batch: &[Record]) -> Result<(), SqliteError> {
+        for record in batch {
+            try!(self.connection.execute_batch("SAVEPOINT record"));
+            let written = match *record {
+                Record::Fetch(ref fetch) => self.write_fetch(fetch),
+                Record::Page(ref page) => self.write_page(page),
+            };
+            match written {
+                Ok(()) => try!(self.connection.execute_batch("RELEASE record")),
+                Err(e) => {
+                    error!("Error writing {} to SQLite, skipping it: {}", record.url(), e);
+                    try!(self.connection.execute_batch("ROLLBACK TO record; RELEASE record"));
+                    // Hosts inserted for the record are rolled back too
+                    self.host_ids.clear();
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn write_fetch(&mut self, fetch: &Fetch) -> Result<(), SqliteError> {
+        let host_id = try!(self.host_id(&fetch.url));
+        let status = fetch.status.map(|status| status as i64);
+        try!(self.connection.execute(
+            "INSERT INTO fetches (timestamp, url, host_id, depth, status, content_type, \
+             download_time) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            &[&fetch.timestamp, &fetch.url, &host_id, &(fetch.depth as i64), &status,
+              &fetch.content_type, &fetch.download_time]));
+        Ok(())
+    }
+
+    fn write_page(&mut self, page: &CrawlResult) -> Result<(), SqliteError> {
+        let host_id = try!(self.host_id(&page.url));
+        let text = if self.fields.text { Some(page.text.as_str()) } else { None };
+        let body = if self.fields.body { Some(page.body.as_str()) } else { None };
+        let params: &[&ToSql] = &[
+            &page.url, &page.original_url, &host_id, &page.referrer, &(page.depth as i64),
+            &(page.status as i64), &page.content_type, &page.fetched_at, &page.download_time,
+            &(page.body_length as i64), &(page.truncated as i64), &page.encoding,
+            &(page.encoding_guessed as i64), &(page.near_duplicate as i64), &page.title,
+            &page.description, &page.lang, &text, &body, &(page.body_base64 as i64)];
+        try!(self.connection.execute(
+            "INSERT INTO pages (url, original_url, host_id, referrer, depth, status, \
+             content_type, fetched_at, download_time, body_length, truncated, encoding, \
+             encoding_guessed, near_duplicate, title, description, lang, text, body, \
+             body_base64) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params));
+        let page_id = self.connection.last_insert_rowid();
+        for &(ref name, ref value) in &page.headers {
+            try!(self.connection.execute(
+                "INSERT INTO headers (page_id, name, value) VALUES (?, ?, ?)",
+                &[&page_id, name, value]));
+        }
+        for link in &page.links {
+            try!(self.connection.execute(
+                "INSERT INTO links (page_id, url, text) VALUES (?, ?, ?)",
+                &[&page_id, &link.url, &link.text]));
+        }
+        Ok(())
+    }
+
+    fn host_id(&mut self, url: &str) -> Result<Option<i64>, SqliteError> {
+        let host = match Url::parse(url).ok().and_then(|url| url.host_str().map(String::from)) {
+            Some(host) => host,
+            None => return Ok(None),
+        };
+        if let Some(&host_id) = self.host_ids.get(&host) {
+            return Ok(Some(host_id));
+        }
+        try!(self.connection.execute(
+            "INSERT OR IGNORE INTO hosts (name) VALUES (?)", &[&host]));
+        let host_id: i64 = try!(self.connection.query_row(
+            "SELECT id FROM hosts WHERE name = ?", &[&host], |row| row.get(0)));
+        self.host_ids.insert(host, host_id);
+        Ok(Some(host_id))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use rusqlite::Connection;
+
+    use request::Request;
+    use result::{CrawlResult, LinkRecord, ResultFields};
+    use sinks::Sink;
+    use super::*;
+    use super::{migrate, schema_version};
+
+    fn count(connection: &Connection, query: &str) -> i64 {
+        connection.query_row(query, &[], |row| row.get(0)).unwrap()
+    }
+
+    fn page(url: &str) -> CrawlResult {
+        CrawlResult {
+            body: "<p>a</p>".to_owned(),
+            url: url.to_owned(),
+            original_url: url.to_owned(),
+            status: 200,
+            headers: vec![("Server".to_owned(), "nginx".to_owned())],
+            content_type: Some("text/html".to_owned()),
+            fetched_at: "2016-07-01T12:00:00Z".to_owned(),
+            download_time: 0.5,
+            body_length: 8,
+            encoding: "utf-8".to_owned(),
+            encoding_guessed: true,
+            title: Some("A".to_owned()),
+            text: "a".to_owned(),
+            links: vec![LinkRecord { url: "http://b.com/".to_owned(), text: "b".to_owned() }],
+            .. CrawlResult::default()
+        }
+    }
+
+    #[test]
+    fn test_sqlite_writer() {
+        let path = env::temp_dir().join("crawler-test-sqlite.db");
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+        {
+            let mut writer = SqliteWriter::new(path, &ResultFields::default()).unwrap();
+            writer.write_response(&Request::from_str("http://a.com/"), &None);
+            writer.write_response(&Request::from_str("http://b.com/"), &None);
+            writer.write_result(&page("http://a.com/"));
+            writer.close();
+        }
+        // Opening again does not apply migrations twice
+        SqliteWriter::new(path, &ResultFields::default()).unwrap();
+        let connection = Connection::open(path).unwrap();
+        assert_eq!(schema_version(&connection).unwrap(), MIGRATIONS.len());
+        assert_eq!(count(&connection, "SELECT COUNT(*) FROM hosts"), 2);
+        assert_eq!(count(&connection, "SELECT COUNT(*) FROM fetches WHERE status IS NULL"), 2);
+        assert_eq!(count(&connection, "SELECT COUNT(*) FROM pages p JOIN hosts h \
+                                       ON p.host_id = h.id WHERE h.name = 'a.com'"), 1);
+        assert_eq!(count(&connection, "SELECT COUNT(*) FROM pages WHERE truncated = 0"), 1);
+        assert_eq!(count(&connection, "SELECT COUNT(*) FROM headers"), 1);
+        assert_eq!(count(&connection, "SELECT COUNT(*) FROM links"), 1);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_skip_bad_record() {
+        let path = env::temp_dir().join("crawler-test-sqlite-bad-record.db");
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+        {
+            let connection = Connection::open(path).unwrap();
+            migrate(&connection).unwrap();
+            connection.execute_batch(
+                "CREATE TRIGGER bad_page BEFORE INSERT ON pages WHEN NEW.url = 'http://c.com/' \
+                 BEGIN SELECT RAISE(ABORT, 'bad page'); END").unwrap();
+        }
+        let mut writer = SqliteWriter::new(path, &ResultFields::default()).unwrap();
+        writer.write_result(&page("http://a.com/"));
+        writer.write_result(&page("http://c.com/"));
+        writer.write_result(&page("http://b.com/"));
+        writer.write_response(&Request::from_str("http://c.com/"), &None);
+        writer.close();
+        let connection = Connection::open(path).unwrap();
+        assert_eq!(count(&connection, "SELECT COUNT(*) FROM pages"), 2);
+        assert_eq!(count(&connection, "SELECT COUNT(*) FROM links"), 2);
+        assert_eq!(count(&connection, "SELECT COUNT(*) FROM fetches f JOIN hosts h \
+                                       ON f.host_id = h.id WHERE h.name = 'c.com'"), 1);
+        fs::remove_file(path).unwrap();
+    }
+}
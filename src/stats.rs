@@ -1,7 +1,12 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
 
+use proxy::ProxyPool;
 use response::Response;
 use queue::RequestQueue;
+use robots::RobotsOutcome;
 
 
 pub struct CrawlStats {
@@ -9,6 +14,10 @@ pub struct CrawlStats {
     last_report: Instant,
     last_stats: Stats,
     all_stats: Stats,
+    /// Number of connections opened by the client
+    n_connections: Arc<AtomicUsize>,
+    /// Per-proxy stats are reported if proxies are used
+    proxy_pool: Option<Arc<Mutex<ProxyPool>>>,
 }
 
 struct Stats {
@@ -16,6 +25,20 @@ struct Stats {
     n_requests: u64,
     n_responses: u64,
     n_read_responses: u64,
+    n_robots_fetched: u64,
+    n_robots_3xx: u64,
+    n_robots_4xx: u64,
+    n_robots_5xx: u64,
+    n_robots_failed: u64,
+    n_encoding_guessed: u64,
+    encodings: HashMap<String, u64>,
+    n_near_duplicates: u64,
+    near_duplicates: HashMap<String, u64>,
+    n_truncated: u64,
+    truncated: HashMap<String, u64>,
+    n_connections: u64,
+    n_wire_bytes: u64,
+    n_decoded_bytes: u64,
     // TODO - ideally we want to know the number of text responses
     // TODO - hashmap with return codes
 }
@@ -27,6 +50,20 @@ impl Stats {
             n_requests: 0,
             n_responses: 0,
             n_read_responses: 0,
+            n_robots_fetched: 0,
+            n_robots_3xx: 0,
+            n_robots_4xx: 0,
+            n_robots_5xx: 0,
+            n_robots_failed: 0,
+            n_encoding_guessed: 0,
+            encodings: HashMap::new(),
+            n_near_duplicates: 0,
+            near_duplicates: HashMap::new(),
+            n_truncated: 0,
+            truncated: HashMap::new(),
+            n_connections: 0,
+            n_wire_bytes: 0,
+            n_decoded_bytes: 0,
         }
     }
 
@@ -37,6 +74,45 @@ impl Stats {
             if response.body.is_some() {
                 self.n_read_responses += 1;
             }
+            self.n_wire_bytes += response.wire_size as u64;
+            self.n_decoded_bytes += response.decoded_size as u64;
+        }
+    }
+
+    fn record_robots(&mut self, outcome: RobotsOutcome) {
+        match outcome {
+            RobotsOutcome::Fetched => self.n_robots_fetched += 1,
+            RobotsOutcome::Redirect => self.n_robots_3xx += 1,
+            RobotsOutcome::ClientError => self.n_robots_4xx += 1,
+            RobotsOutcome::ServerError => self.n_robots_5xx += 1,
+            RobotsOutcome::Failed => self.n_robots_failed += 1,
+        }
+    }
+
+    fn record_encoding(&mut self, encoding: &str, guessed: bool) {
+        *self.encodings.entry(encoding.to_owned()).or_insert(0) += 1;
+        if guessed {
+            self.n_encoding_guessed += 1;
+        }
+    }
+
+    fn record_near_duplicate(&mut self, domain: &str) {
+        self.n_near_duplicates += 1;
+        *self.near_duplicates.entry(domain.to_owned()).or_insert(0) += 1;
+    }
+
+    fn record_truncated(&mut self, domain: &str) {
+        self.n_truncated += 1;
+        *self.truncated.entry(domain.to_owned()).or_insert(0) += 1;
+    }
+
+    /// Share of requests made over already open connections.
+    fn reuse_ratio(&self) -> Option<f64> {
+        if self.n_requests > 0 {
+            let reused = self.n_requests.saturating_sub(self.n_connections);
+            Some(reused as f64 / self.n_requests as f64)
+        } else {
+            None
         }
     }
 
@@ -44,6 +120,38 @@ impl Stats {
         info!("Requests:             {}", self.n_requests);
         info!("Responses:            {}", self.n_responses);
         info!("Read responses:       {}", self.n_read_responses);
+        info!("Robots.txt:           {} fetched, {} 3xx (allow), {} 4xx (allow), \
+               {} 5xx (disallow), {} failed (disallow)",
+              self.n_robots_fetched, self.n_robots_3xx, self.n_robots_4xx, self.n_robots_5xx,
+              self.n_robots_failed);
+        let mut encodings: Vec<_> = self.encodings.iter().collect();
+        encodings.sort_by(|a, b| b.1.cmp(a.1));
+        let encodings: Vec<_> = encodings.iter().take(5)
+            .map(|&(encoding, count)| format!("{} {}", encoding, count)).collect();
+        info!("Encodings:            {} ({} guessed)",
+              encodings.join(", "), self.n_encoding_guessed);
+        if self.n_near_duplicates > 0 {
+            let mut domains: Vec<_> = self.near_duplicates.iter().collect();
+            domains.sort_by(|a, b| b.1.cmp(a.1));
+            let domains: Vec<_> = domains.iter().take(5)
+                .map(|&(domain, count)| format!("{} {}", domain, count)).collect();
+            info!("Near-duplicates:      {} (top domains: {})",
+                  self.n_near_duplicates, domains.join(", "));
+        }
+        if self.n_truncated > 0 {
+            let mut domains: Vec<_> = self.truncated.iter().collect();
+            domains.sort_by(|a, b| b.1.cmp(a.1));
+            let domains: Vec<_> = domains.iter().take(5)
+                .map(|&(domain, count)| format!("{} {}", domain, count)).collect();
+            info!("Truncated bodies:     {} (top domains: {})",
+                  self.n_truncated, domains.join(", "));
+        }
+        info!("Body bytes:           {} received, {} decoded",
+              self.n_wire_bytes, self.n_decoded_bytes);
+        if let Some(reuse_ratio) = self.reuse_ratio() {
+            info!("Connections:          {} opened, reuse ratio {:.2}",
+                  self.n_connections, reuse_ratio);
+        }
         let dt = self.start.elapsed();
         let dt_s: f64 = dt.as_secs() as f64 + 1e-9 * dt.subsec_nanos() as f64;
         info!("rps (read responses): {:.0}", self.n_read_responses as f64 / dt_s);
@@ -51,12 +159,15 @@ impl Stats {
 }
 
 impl CrawlStats {
-    pub fn new(report_every: Duration) -> Self {
+    pub fn new(report_every: Duration, n_connections: Arc<AtomicUsize>,
+               proxy_pool: Option<Arc<Mutex<ProxyPool>>>) -> Self {
         CrawlStats {
             report_every: report_every,
             last_report: Instant::now(),
             last_stats: Stats::new(),
             all_stats: Stats::new(),
+            n_connections: n_connections,
+            proxy_pool: proxy_pool,
         }
     }
 
@@ -65,18 +176,100 @@ impl CrawlStats {
         self.all_stats.record_response(response);
     }
 
+    pub fn record_robots(&mut self, outcome: RobotsOutcome) {
+        self.last_stats.record_robots(outcome);
+        self.all_stats.record_robots(outcome);
+    }
+
+    pub fn record_encoding(&mut self, encoding: &str, guessed: bool) {
+        self.last_stats.record_encoding(encoding, guessed);
+        self.all_stats.record_encoding(encoding, guessed);
+    }
+
+    pub fn record_near_duplicate(&mut self, domain: &str) {
+        self.last_stats.record_near_duplicate(domain);
+        self.all_stats.record_near_duplicate(domain);
+    }
+
+    pub fn record_truncated(&mut self, domain: &str) {
+        self.last_stats.record_truncated(domain);
+        self.all_stats.record_truncated(domain);
+    }
+
     pub fn maybe_report(&mut self, request_queue: &RequestQueue) {
         let elapsed = self.last_report.elapsed();
         if elapsed < self.report_every {
             return;
         }
-        info!("Request queue: {} domains, {} requests pending",
-              request_queue.get_n_domains(), request_queue.get_n_pending());
+        info!("Request queue: {} domains, {} requests pending, {} forbidden by robots.txt",
+              request_queue.get_n_domains(), request_queue.get_n_pending(),
+              request_queue.get_n_robots_disallowed());
+        let seen_requests = request_queue.get_seen_requests();
+        match (seen_requests.fill_ratio(), seen_requests.false_positive_rate()) {
+            (Some(fill_ratio), Some(false_positive_rate)) =>
+                info!("Seen requests: {}, fill ratio {:.3}, \
+                       estimated false positive rate {:.5}",
+                      seen_requests.len(), fill_ratio, false_positive_rate),
+            _ => info!("Seen requests: {}", seen_requests.len()),
+        }
+        self.update_connections();
         info!("Crawl stats (last {:?} s):", self.report_every.as_secs());
         self.last_stats.report();
         info!("Crawl stats (overall):");
         self.all_stats.report();
+        if let Some(ref proxy_pool) = self.proxy_pool {
+            proxy_pool.lock().unwrap().report();
+        }
         self.last_stats = Stats::new();
         self.last_report = Instant::now();
     }
+
+    /// Take the number of connections opened since the last report from the client counter.
+    fn update_connections(&mut self) {
+        let n_connections = self.n_connections.load(Ordering::Relaxed) as u64;
+        self.last_stats.n_connections = n_connections - self.all_stats.n_connections;
+        self.all_stats.n_connections = n_connections;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use hyper::status::StatusCode;
+
+    use response::{Response, test_response};
+    use super::*;
+    use super::Stats;
+
+    fn response() -> Option<Response> {
+        Some(test_response(StatusCode::Ok, None))
+    }
+
+    #[test]
+    fn test_reuse_ratio() {
+        let n_connections = Arc::new(AtomicUsize::new(0));
+        let mut stats = CrawlStats::new(Duration::from_secs(20), n_connections.clone(), None);
+        stats.update_connections();
+        assert_eq!(stats.all_stats.reuse_ratio(), None);
+        for _ in 0..4 {
+            stats.record_response(&response());
+        }
+        n_connections.store(1, Ordering::Relaxed);
+        stats.update_connections();
+        assert_eq!(stats.last_stats.reuse_ratio(), Some(0.75));
+        // Failed requests are counted too
+        stats.last_stats = Stats::new();
+        stats.record_response(&response());
+        stats.record_response(&None);
+        n_connections.store(3, Ordering::Relaxed);
+        stats.update_connections();
+        assert_eq!(stats.last_stats.n_connections, 2);
+        assert_eq!(stats.last_stats.reuse_ratio(), Some(0.0));
+        assert_eq!(stats.all_stats.n_connections, 3);
+        assert_eq!(stats.all_stats.reuse_ratio(), Some(0.5));
+    }
 }
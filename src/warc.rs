@@ -0,0 +1,319 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base32::{self, Alphabet};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use hyper::header::{Encoding, TransferEncoding};
+use rand;
+use sha1::Sha1;
+
+use request::Request;
+use response::Response;
+use sinks::Sink;
+
+
+/// Writes requests and responses into gzip-per-record compressed WARC/1.1 files,
+/// starting a new file when current one exceeds max_size.
+pub struct WarcWriter {
+    prefix: String,
+    max_size: u64,
+    user_agent: String,
+    keep_alive: bool,
+    file: Option<File>,
+    file_size: u64,
+    n_files: u32,
+}
+
+impl WarcWriter {
+    pub fn new(prefix: &str, max_size: u64, user_agent: &str, keep_alive: bool) -> Self {
+        WarcWriter {
+            prefix: prefix.to_owned(),
+            max_size: max_size,
+            user_agent: user_agent.to_owned(),
+            keep_alive: keep_alive,
+            file: None,
+            file_size: 0,
+            n_files: 0,
+        }
+    }
+
+    /// Write response record and the request record concurrent to it.
+    /// Response is stored as received: with its HTTP version, headers and the body before
+    /// Content-Encoding was decoded. Chunked bodies are written as a single chunk,
+    /// as chunk boundaries are not kept by the client, and WARC-Payload-Digest is
+    /// the digest of the body without transfer encoding. Bodies that were not read
+    /// or were cut at max_body_size are marked with WARC-Truncated.
+    fn write(&mut self, request: &Request, response: &Response) {
+        let date = format_timestamp(response.fetched_at);
+        let url = request.url.as_str();
+        let body: &[u8] = match (&response.raw_body, &response.body) {
+            (&Some(ref body), _) | (&None, &Some(ref body)) => body,
+            (&None, &None) => &[],
+        };
+        let mut response_block = format!(
+            "{} {}\r\n{}\r\n", response.version, response.status, response.raw_headers)
+            .into_bytes();
+        let is_chunked = response.raw_headers.get::<TransferEncoding>()
+            .map_or(false, |&TransferEncoding(ref encodings)| {
+                encodings.iter().any(|encoding| *encoding == Encoding::Chunked)
+            });
+        if is_chunked {
+            if !body.is_empty() {
+                response_block.extend_from_slice(format!("{:x}\r\n", body.len()).as_bytes());
+                response_block.extend_from_slice(body);
+                response_block.extend_from_slice(b"\r\n");
+            }
+            response_block.extend_from_slice(b"0\r\n\r\n");
+        } else {
+            response_block.extend_from_slice(body);
+        }
+        let response_id = record_id();
+        let payload_digest = digest(body);
+        let mut fields = vec![
+            ("WARC-Type", "response"),
+            ("WARC-Record-ID", &response_id[..]),
+            ("WARC-Date", &date[..]),
+            ("WARC-Target-URI", url),
+            ("WARC-Payload-Digest", &payload_digest[..]),
+            ("Content-Type", "application/http; msgtype=response"),
+        ];
+        if response.body.is_none() || response.truncated {
+            fields.push(("WARC-Truncated", "length"));
+        }
+        self.write_record(&fields, &response_block);
+        let request_block = self.request_block(request).into_bytes();
+        self.write_record(&[
+            ("WARC-Type", "request"),
+            ("WARC-Record-ID", &record_id()),
+            ("WARC-Date", &date),
+            ("WARC-Target-URI", url),
+            ("WARC-Concurrent-To", &response_id),
+            ("Content-Type", "application/http; msgtype=request"),
+        ], &request_block);
+        if self.file_size >= self.max_size {
+            self.file = None;
+        }
+    }
+
+    /// Request as it was sent by the downloader.
+    fn request_block(&self, request: &Request) -> String {
+        let ref url = request.url;
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_owned(),
+        };
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or(""), port),
+            None => url.host_str().unwrap_or("").to_owned(),
+        };
+        let connection = if self.keep_alive { "" } else { "Connection: close\r\n" };
+        format!("GET {} HTTP/1.1\r\nHost: {}\r\n{}User-Agent: {}\r\n\
+                 Accept-Encoding: gzip, deflate, br\r\n\r\n",
+                path, host, connection, self.user_agent)
+    }
+
+    fn write_record(&mut self, fields: &[(&str, &str)], block: &[u8]) {
+        if self.file.is_none() {
+            self.open_file();
+        }
+        let block_digest = digest(block);
+        let mut header = "WARC/1.1\r\n".to_owned();
+        for &(name, value) in fields {
+            header.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        header.push_str(&format!("WARC-Block-Digest: {}\r\n", block_digest));
+        header.push_str(&format!("Content-Length: {}\r\n\r\n", block.len()));
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::Default);
+        encoder.write_all(header.as_bytes()).unwrap();
+        encoder.write_all(block).unwrap();
+        encoder.write_all(b"\r\n\r\n").unwrap();
+        let compressed = encoder.finish().unwrap();
+        if let Some(ref mut file) = self.file {
+            file.write_all(&compressed).unwrap();
+            file.flush().unwrap();
+        }
+        self.file_size += compressed.len() as u64;
+    }
+
+    fn open_file(&mut self) {
+        let now = SystemTime::now();
+        let timestamp: String = format_timestamp(now).chars()
+            .filter(|c| c.is_digit(10)).collect();
+        let filename = format!("{}-{}-{:05}.warc.gz", self.prefix, timestamp, self.n_files);
+        let path = PathBuf::from(&filename);
+        info!("Writing WARC records to {}", filename);
+        self.file = Some(File::create(&path).unwrap());
+        self.file_size = 0;
+        self.n_files += 1;
+        let info = format!("software: crawler/{}\r\nformat: WARC File Format 1.1\r\n\
+                            http-header-user-agent: {}\r\n",
+                           env!("CARGO_PKG_VERSION"), self.user_agent);
+        let filename = path.file_name().and_then(|name| name.to_str()).unwrap_or("")
+            .to_owned();
+        self.write_record(&[
+            ("WARC-Type", "warcinfo"),
+            ("WARC-Record-ID", &record_id()),
+            ("WARC-Date", &format_timestamp(now)),
+            ("WARC-Filename", &filename),
+            ("Content-Type", "application/warc-fields"),
+        ], info.as_bytes());
+    }
+}
+
+impl Sink for WarcWriter {
+    fn write_response(&mut self, request: &Request, response: &Option<Response>) {
+        if let Some(ref response) = *response {
+            self.write(request, response);
+        }
+    }
+}
+
+/// Random (version 4) UUID urn.
+fn record_id() -> String {
+    let high = rand::random::<u64>();
+    let low = rand::random::<u64>();
+    // Set version to 4 and variant to RFC 4122
+    let high = (high & !0xf000) | 0x4000;
+    let low = (low & !(0xc << 60)) | (0x8 << 60);
+    format!("<urn:uuid:{:08x}-{:04x}-{:04x}-{:04x}-{:012x}>",
+            high >> 32, (high >> 16) & 0xffff, high & 0xffff,
+            low >> 48, low & 0xffffffffffff)
+}
+
+/// Format time as ISO 8601 in UTC, e.g. "2016-07-01T12:00:00Z".
+pub fn format_timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, day_secs) = (secs / 86400, secs % 86400);
+    // Civil from days algorithm by Howard Hinnant
+    let z = days as i64 + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year, month, day, day_secs / 3600, day_secs % 3600 / 60, day_secs % 60)
+}
+
+/// SHA-1 digest in base32, as used in WARC-Block-Digest and WARC-Payload-Digest.
+fn digest(data: &[u8]) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(data);
+    format!("sha1:{}", base32::encode(Alphabet::RFC4648 { padding: false },
+                                      &sha1.digest().bytes()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use flate2::read::MultiGzDecoder;
+    use hyper::header::{ContentEncoding, ContentLength, Encoding, Headers, TransferEncoding};
+    use hyper::status::StatusCode;
+    use hyper::version::HttpVersion;
+
+    use request::Request;
+    use response::{Response, test_response};
+    use sinks::Sink;
+    use super::*;
+    use super::{digest, record_id};
+
+    #[test]
+    fn test_digest() {
+        assert_eq!(digest(b""), "sha1:3I42H3S6NNFQ2MSVX7XZKYAYSCX5QBYJ");
+        assert_eq!(digest(b"abc"), "sha1:VGMT4NSHA2AWVOR6EVYXQUGCNSONBWE5");
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(UNIX_EPOCH + Duration::from_secs(1467374400)),
+                   "2016-07-01T12:00:00Z");
+        assert_eq!(format_timestamp(UNIX_EPOCH + Duration::from_secs(951782399)),
+                   "2000-02-28T23:59:59Z");
+    }
+
+    #[test]
+    fn test_record_id() {
+        let id = record_id();
+        assert!(id.starts_with("<urn:uuid:") && id.ends_with(">"));
+        assert_eq!(id.len(), "<urn:uuid:>".len() + 36);
+        assert!(id != record_id());
+    }
+
+    #[test]
+    fn test_warc_writer() {
+        let dir = env::temp_dir().join("crawler-test-warc");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let prefix = dir.join("crawl").to_str().unwrap().to_owned();
+        let response = |version: HttpVersion, raw_headers: Headers, raw_body: Option<&[u8]>,
+                        body: Option<&[u8]>| Some(Response {
+            version: version,
+            raw_headers: raw_headers,
+            raw_body: raw_body.map(|body| body.to_vec()),
+            fetched_at: UNIX_EPOCH + Duration::from_secs(1467374400),
+            .. test_response(StatusCode::Ok, body)
+        });
+        let mut plain_headers = Headers::new();
+        plain_headers.set(ContentLength(8));
+        let mut encoded_headers = Headers::new();
+        encoded_headers.set(TransferEncoding(vec![Encoding::Chunked]));
+        encoded_headers.set(ContentEncoding(vec![Encoding::Gzip]));
+        {
+            let mut writer = WarcWriter::new(&prefix, 1024 * 1024, "crawler", true);
+            writer.write_response(
+                &Request::from_str("http://a.com/"),
+                &response(HttpVersion::Http10, plain_headers, None, Some(b"<p>a</p>")));
+            writer.write_response(
+                &Request::from_str("http://a.com/b"),
+                &response(HttpVersion::Http11, encoded_headers, Some(b"gzip"), Some(b"<p>b")));
+            writer.write_response(
+                &Request::from_str("http://a.com/c.zip"),
+                &response(HttpVersion::Http11, Headers::new(), None, None));
+        }
+        let paths: Vec<_> = fs::read_dir(&dir).unwrap()
+            .map(|entry| entry.unwrap().path()).collect();
+        assert_eq!(paths.len(), 1);
+        let mut contents = String::new();
+        MultiGzDecoder::new(File::open(&paths[0]).unwrap()).unwrap()
+            .read_to_string(&mut contents).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let records: Vec<_> = contents.split("WARC/1.1\r\n").skip(1).collect();
+        assert_eq!(records.len(), 7);
+        assert!(records[0].contains("WARC-Type: warcinfo\r\n"));
+        let response = records[1];
+        assert!(response.contains("WARC-Type: response\r\n"));
+        assert!(response.contains("WARC-Target-URI: http://a.com/\r\n"));
+        assert!(response.contains("WARC-Date: 2016-07-01T12:00:00Z\r\n"));
+        assert!(response.contains(&format!("WARC-Payload-Digest: {}\r\n", digest(b"<p>a</p>"))));
+        assert!(!response.contains("WARC-Truncated"));
+        assert!(response.contains("\r\n\r\nHTTP/1.0 200 OK\r\nContent-Length: 8\r\n"));
+        assert!(response.ends_with("\r\n\r\n<p>a</p>\r\n\r\n"));
+        let block = &response[response.find("HTTP/1.0").unwrap()..response.len() - 4];
+        assert!(response.contains(&format!("WARC-Block-Digest: {}\r\n",
+                                           digest(block.as_bytes()))));
+        assert!(response.contains(&format!("Content-Length: {}\r\n", block.len())));
+        assert!(records[2].contains("WARC-Type: request\r\n"));
+        assert!(records[2].contains("WARC-Date: 2016-07-01T12:00:00Z\r\n"));
+        assert!(records[2].contains("GET / HTTP/1.1\r\nHost: a.com\r\n"));
+        // Encoded body is written as received, in a single chunk
+        let response = records[3];
+        assert!(response.contains("\r\n\r\nHTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(response.contains("Content-Encoding: gzip\r\n"));
+        assert!(response.contains(&format!("WARC-Payload-Digest: {}\r\n", digest(b"gzip"))));
+        assert!(response.ends_with("\r\n\r\n4\r\ngzip\r\n0\r\n\r\n\r\n\r\n"));
+        assert!(records[5].contains("WARC-Truncated: length\r\n"));
+    }
+}